@@ -56,8 +56,12 @@ impl<T: Clone> CardMatrix<T> {
         (row_i * self.row_len)..((row_i + 1) * self.row_len)
     }
 
+    pub fn iter_rows(&self) -> impl Iterator<Item = &[T]> {
+        self.values.chunks(self.row_len)
+    }
+
     pub fn col(&self, idx: usize) -> Vec<T> {
-        (0..self.rows.len()).into_iter()
+        (0..self.rows.len())
             .map(|row_i| self.values[row_i * self.row_len + idx].clone())
             .collect()
     }
@@ -1,13 +1,84 @@
-use std::collections::{HashSet};
-use rand::{RngCore, seq::SliceRandom};
+use std::{collections::{HashSet}, fmt};
+use rand::{Rng, RngCore, seq::SliceRandom};
 
-use crate::{policy, utils};
-use crate::game::{Card, GameState, Rules};
+use crate::{mcmc, utils};
+use crate::smc::{ParticleFilter};
+use crate::stats::{LogWeightStats};
+use crate::utils::{SearchBudget};
+use crate::game::{Card, GameEngine, GameState, Rules};
+use crate::policy::{Policy};
 use crate::card_matrix::{CardMatrix};
 
+/// The method used to estimate the distribution of hands.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Inference {
+    /// Weights uniformly sampled deals by their likelihood (importance sampling).
+    Importance,
+    /// Samples the deals from the posterior using Markov chain Monte Carlo (see `mcmc`).
+    Mcmc,
+    /// Updates a set of particles (deals) round by round (see `smc`).
+    Smc,
+    /// Enumerates all possible deals, which is only tractable for very small decks (see
+    /// `is_exact_tractable()`).
+    Exact,
+}
+
+impl Inference {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Inference::Importance => "importance",
+            Inference::Mcmc => "mcmc",
+            Inference::Smc => "smc",
+            Inference::Exact => "exact",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Inference> {
+        [Inference::Importance, Inference::Mcmc, Inference::Smc, Inference::Exact].iter().cloned()
+            .find(|inference| inference.name() == name)
+    }
+}
+
+/// The method used by `HandsDistrib::sample()` to draw the hands.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Sampling {
+    /// Picks one of the weighted joint samples from the estimation.
+    Joint,
+    /// Picks one of the joint samples after they were resampled to equal weights.
+    Resampled,
+    /// Assigns the cards to owners one by one, using only the probabilities of owners of
+    /// every card, so the correlations between the cards are lost.
+    Marginal,
+}
+
+impl Sampling {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Sampling::Joint => "joint",
+            Sampling::Resampled => "resampled",
+            Sampling::Marginal => "marginal",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Sampling> {
+        [Sampling::Joint, Sampling::Resampled, Sampling::Marginal].iter().cloned()
+            .find(|sampling| sampling.name() == name)
+    }
+}
+
 #[derive(Debug)]
 pub struct HandsDistrib {
     card_probs: CardMatrix<f32>,
+    /// Weighted samples of the remaining hands of all owners (owner 0 is the deck). The
+    /// weights have mean 1.
+    samples: Vec<Vec<Vec<Card>>>,
+    weights: Vec<f32>,
+    sampling: Sampling,
+    /// The statistics of the importance weights of the samples (for
+    /// `Inference::Importance`).
+    estimate_weights: Option<LogWeightStats>,
+    /// The acceptance rate of the Markov chains that produced the samples.
+    acceptance_rate: Option<f32>,
     mean_owner_probs: Vec<f32>,
     cards: Vec<Card>,
     hand_len: usize,
@@ -16,35 +87,75 @@ pub struct HandsDistrib {
 
 impl HandsDistrib {
     /// Estimates the distribution from previous state of the game, assuming that other
-    /// players follow policy_1().
-    pub fn estimate(rng: &mut dyn RngCore, rules: &Rules, state: &GameState)
-        -> HandsDistrib
+    /// players follow the `policy`. The estimation uses the given `inference` method in
+    /// `thread_count` threads until the `budget` is exhausted.
+    pub fn estimate(
+        rng: &mut dyn RngCore, rules: &Rules, policy: &dyn Policy, state: &GameState,
+        inference: Inference, thread_count: usize, budget: SearchBudget,
+    ) -> HandsDistrib
     {
-        // `known_hands` lists the cards that are known to be in hand of a given owner.
-        // `unknown_cards` is a set of cards with unknown owner.
-        let mut known_hands = vec![Vec::new(); state.player_count];
-        let mut unknown_cards = rules.cards().collect::<HashSet<Card>>();
-        for round in state.past_rounds.iter() {
-            for player_i in 1..state.player_count {
-                let card = round.actions[player_i];
-                known_hands[player_i].push(card);
-                unknown_cards.remove(&card);
-            }
+        match inference {
+            Inference::Importance => {
+                let (full_samples, log_weights) = sample_hands_importance(rng, rules, policy, state,
+                    thread_count, budget);
+                let mut distrib = HandsDistrib::from_samples(rules, state, full_samples,
+                    &log_weights, None);
+                let mut estimate_weights = LogWeightStats::new();
+                log_weights.iter().for_each(|&log_weight| estimate_weights.add(log_weight));
+                distrib.estimate_weights = Some(estimate_weights);
+                distrib
+            },
+            Inference::Mcmc => {
+                let (full_samples, acceptance_rate) = mcmc::sample_hands(rng, rules, policy, state,
+                    thread_count, budget);
+                let log_weights = vec![0.; full_samples.len()];
+                HandsDistrib::from_samples(rules, state, full_samples, &log_weights,
+                    Some(acceptance_rate))
+            },
+            Inference::Smc => {
+                let mut filter = ParticleFilter::new(rng, rules, state, budget);
+                filter.update(rng, rules, policy, state, thread_count);
+                filter.to_distrib(rules, state)
+            },
+            Inference::Exact => HandsDistrib::exact(rules, policy, state),
         }
-        state.past_rounds.iter()
-            .map(|r| r.table.cards()).flatten()
-            .chain(state.table.cards())
-            .chain(state.my_hand.iter())
-            .for_each(|card| { unknown_cards.remove(card); });
+    }
+
+    /// Computes the exact posterior distribution by enumerating all deals of the unknown
+    /// cards, weighted by their likelihood. The number of deals must be small (see
+    /// `is_exact_tractable()`).
+    pub fn exact(rules: &Rules, policy: &dyn Policy, state: &GameState) -> HandsDistrib {
+        let (known_hands, unknown_cards) = split_cards(rules, state);
+        let full_samples = enumerate_deals(rules, &known_hands, &unknown_cards);
+        let log_weights = full_samples.iter()
+            .map(|hands| calc_hands_log_prob(rules, policy, state, hands))
+            .collect::<Vec<_>>();
+        HandsDistrib::from_samples(rules, state, full_samples, &log_weights, None)
+    }
 
-        // convert the `unknown_cards` set into a vec and sort it to get a deterministic
-        // result (without sorting the order depends on the HashSet randomization).
-        let mut unknown_cards = unknown_cards.into_iter().collect::<Vec<Card>>();
-        unknown_cards.sort_unstable_by_key(|&card| card.idx());
+    /// Creates the distribution from weighted samples of full hands of all owners (in the
+    /// format of `sample_hands_uniform()`) with the given logarithms of weights.
+    pub fn from_samples(
+        rules: &Rules, state: &GameState,
+        full_samples: Vec<Vec<Vec<Card>>>, log_weights: &[f32],
+        acceptance_rate: Option<f32>,
+    ) -> HandsDistrib
+    {
+        let (known_hands, mut unknown_cards) = split_cards(rules, state);
+        let samples = full_samples.into_iter()
+            .map(|hands| remaining_hands(&known_hands, hands))
+            .collect::<Vec<_>>();
+
+        // normalize the weights to mean 1, so that they can be used directly as the
+        // weights of the samples returned by `sample()`
+        let max_log_weight = log_weights.iter().fold(-f32::INFINITY, |x, &y| f32::max(x, y));
+        let mut weights = log_weights.iter()
+            .map(|log_weight| (log_weight - max_log_weight).exp())
+            .collect::<Vec<_>>();
+        let weight_sum = weights.iter().sum::<f32>();
+        weights.iter_mut().for_each(|weight| *weight *= samples.len() as f32 / weight_sum);
 
-        // estimate the probabilities of owners for each unknown card
-        let card_probs = estimate_probs(rng, rules, state,
-            &known_hands, &mut unknown_cards);
+        let card_probs = count_probs(&unknown_cards, state.player_count, &samples, &weights);
 
         // calculate mean probabilities per owner
         let mut mean_owner_probs = (0..state.player_count)
@@ -58,17 +169,74 @@ impl HandsDistrib {
             utils::compare_f32(*card_probs.elem(c2, 0), *card_probs.elem(c1, 0)));
 
         HandsDistrib {
-            card_probs: card_probs,
-            mean_owner_probs: mean_owner_probs,
+            card_probs,
+            samples,
+            weights,
+            sampling: Sampling::Joint,
+            estimate_weights: None,
+            acceptance_rate,
+            mean_owner_probs,
             cards: unknown_cards,
-            hand_len: rules.hand_len - state.past_rounds.len(),
+            hand_len: rules.hand_len - state.observed_rounds().count(),
             player_count: state.player_count,
         }
     }
 
+    /// Adapts the distribution estimated with the `base_policy` to another `policy`, by
+    /// multiplying the weight of every joint sample by the ratio of its likelihoods under
+    /// the two policies. This is cheaper than a new estimate, but the weights degenerate
+    /// when the policies are very different (see `diagnose()`).
+    pub fn reweight(
+        &self, rules: &Rules, base_policy: &dyn Policy, policy: &dyn Policy, state: &GameState,
+    ) -> HandsDistrib
+    {
+        let (known_hands, _) = split_cards(rules, state);
+        let full_samples = self.samples.iter()
+            .map(|hands| full_hands(&known_hands, hands))
+            .collect::<Vec<_>>();
+        let log_weights = full_samples.iter().zip(self.weights.iter())
+            .map(|(hands, weight)| weight.ln()
+                + calc_hands_log_prob(rules, policy, state, hands)
+                - calc_hands_log_prob(rules, base_policy, state, hands))
+            .collect::<Vec<_>>();
+        let mut distrib = HandsDistrib::from_samples(rules, state, full_samples, &log_weights,
+            self.acceptance_rate);
+        distrib.estimate_weights = self.estimate_weights.clone();
+        distrib
+    }
+
+    /// Changes the method used by `sample()`. The `Sampling::Resampled` method replaces the
+    /// samples by a systematic resample, which has the same number of samples with equal
+    /// weights.
+    pub fn set_sampling(&mut self, rng: &mut dyn RngCore, sampling: Sampling) {
+        if sampling == Sampling::Resampled && self.sampling != Sampling::Resampled {
+            let mut pdf = self.weights.clone();
+            utils::normalize_pdf(&mut pdf);
+            self.samples = utils::resample_systematic(rng, &pdf, self.samples.len())
+                .into_iter()
+                .map(|sample_i| self.samples[sample_i].clone())
+                .collect();
+            self.weights = vec![1.; self.samples.len()];
+        }
+        self.sampling = sampling;
+    }
+
     /// Samples hands of owners from this distribution. Returns the hands and a weight of
     /// this sample.
-    pub fn sample(&self, rng: &mut dyn RngCore, _rules: &Rules)
+    pub fn sample(&self, rng: &mut dyn RngCore, rules: &Rules)
+        -> (Vec<Vec<Card>>, f32)
+    {
+        match self.sampling {
+            Sampling::Joint | Sampling::Resampled => {
+                let sample_i = rng.gen_range(0, self.samples.len());
+                (self.samples[sample_i].clone(), self.weights[sample_i])
+            },
+            Sampling::Marginal => self.sample_marginal(rng, rules),
+        }
+    }
+
+    /// Samples the hands card by card from the probabilities of owners of every card.
+    fn sample_marginal(&self, rng: &mut dyn RngCore, rules: &Rules)
         -> (Vec<Vec<Card>>, f32)
     {
         let mut sample_hands = vec![Vec::new(); self.player_count];
@@ -76,9 +244,13 @@ impl HandsDistrib {
             return (sample_hands, 1.);
         }
 
+        // when all cards are dealt, the deck is empty and we never assign cards to it
         let deck_len = self.cards.len() - self.hand_len * (self.player_count - 1);
+        assert!(deck_len == 0 || !rules.all_cards_dealt());
         let mut sample_weight = 1.;
-        let mut owner_is = (0..self.player_count).collect::<Vec<_>>();
+        let mut owner_is = (0..self.player_count)
+            .filter(|&owner_i| owner_i != 0 || deck_len > 0)
+            .collect::<Vec<_>>();
         let mut sample_probs = vec![0.; self.player_count];
         for &card in self.cards.iter() {
             assert!(!owner_is.is_empty());
@@ -108,39 +280,237 @@ impl HandsDistrib {
 
         (sample_hands, sample_weight)
     }
+
+    /// Computes diagnostics of this distribution, drawing `sample_count` samples to
+    /// evaluate the weights returned by `sample()`.
+    pub fn diagnose(&self, rng: &mut dyn RngCore, rules: &Rules, sample_count: usize)
+        -> Diagnostics
+    {
+        let mut sample_weights = LogWeightStats::new();
+        for _ in 0..sample_count {
+            let (_, weight) = self.sample(rng, rules);
+            sample_weights.add(weight.ln());
+        }
+
+        let entropies = self.card_probs.iter_rows()
+            .map(|probs| probs.iter()
+                .filter(|&&prob| prob > 0.)
+                .map(|&prob| -prob * prob.log2())
+                .sum::<f32>())
+            .collect::<Vec<_>>();
+        let mean_owner_entropy =
+            if entropies.is_empty() { 0. }
+            else { entropies.iter().sum::<f32>() / entropies.len() as f32 };
+
+        let estimate_weights = self.estimate_weights.clone();
+        let acceptance_rate = self.acceptance_rate;
+        Diagnostics { estimate_weights, acceptance_rate, sample_weights, mean_owner_entropy }
+    }
 }
 
-/// Estimates the probabilities Pr(owner_i owns card | state) for every unknown card,
-/// assuming that players follow the policy_1(). owner_i = 0 is "the deck", i.e. the
-/// probability that a card is in the deck and not in the hand of any player.
-fn estimate_probs(
-    rng: &mut dyn RngCore, rules: &Rules, state: &GameState,
-    known_hands: &[Vec<Card>], unknown_cards: &mut [Card],
-) -> CardMatrix<f32>
+/// Diagnostics of the approximation of the posterior distribution of hands in
+/// `HandsDistrib`.
+#[derive(Debug, Clone)]
+pub struct Diagnostics {
+    /// Statistics of the importance weights of the hands drawn in the estimation (only for
+    /// `Inference::Importance`).
+    pub estimate_weights: Option<LogWeightStats>,
+    /// The acceptance rate of the Markov chains (for `Inference::Mcmc` and the
+    /// rejuvenation moves of `Inference::Smc`).
+    pub acceptance_rate: Option<f32>,
+    /// Statistics of the weights returned by `HandsDistrib::sample()`.
+    pub sample_weights: LogWeightStats,
+    /// The mean entropy (in bits) of the distributions of owners of the unknown cards.
+    pub mean_owner_entropy: f32,
+}
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let write_weights = |f: &mut fmt::Formatter, weights: &LogWeightStats| write!(f,
+            "effective samples {:.0} of {}, max weight {:.2}", weights.effective_sample_size(),
+            weights.sample_count(), weights.max_weight_fraction());
+        if let Some(ref weights) = self.estimate_weights {
+            write!(f, "estimate: ")?;
+            write_weights(f, weights)?;
+            write!(f, "; ")?;
+        }
+        if let Some(acceptance_rate) = self.acceptance_rate {
+            write!(f, "acceptance rate {:.3}; ", acceptance_rate)?;
+        }
+        write!(f, "sampling: ")?;
+        write_weights(f, &self.sample_weights)?;
+        write!(f, "; owner entropy {:.2} bits", self.mean_owner_entropy)
+    }
+}
+
+impl Diagnostics {
+    /// Returns true if the approximation is degenerate: the estimate is dominated by a few
+    /// hands, or a few samples would dominate the evaluation of actions.
+    pub fn is_degenerate(&self) -> bool {
+        const MIN_ESTIMATE_EFFECTIVE_SAMPLE_SIZE: f32 = 100.;
+        const MIN_SAMPLE_EFFECTIVE_FRACTION: f32 = 0.1;
+        const MAX_WEIGHT_FRACTION: f32 = 0.1;
+        const MIN_ACCEPTANCE_RATE: f32 = 0.01;
+
+        let sample_effective_fraction = self.sample_weights.effective_sample_size()
+            / self.sample_weights.sample_count() as f32;
+        self.estimate_weights.as_ref().is_some_and(|weights|
+                weights.effective_sample_size() < MIN_ESTIMATE_EFFECTIVE_SAMPLE_SIZE
+                || weights.max_weight_fraction() > MAX_WEIGHT_FRACTION)
+            || self.acceptance_rate.is_some_and(|rate| rate < MIN_ACCEPTANCE_RATE)
+            || sample_effective_fraction < MIN_SAMPLE_EFFECTIVE_FRACTION
+            || self.sample_weights.max_weight_fraction() > MAX_WEIGHT_FRACTION
+    }
+}
+
+/// Samples full hands of all owners (in the format of `sample_hands_uniform()`) uniformly
+/// and weights them by the likelihood of the observed rounds (importance sampling),
+/// assuming that players follow the `policy`. Returns the samples with the logarithms of
+/// their weights.
+fn sample_hands_importance(
+    rng: &mut dyn RngCore, rules: &Rules, policy: &dyn Policy, state: &GameState,
+    thread_count: usize, budget: SearchBudget,
+) -> (Vec<Vec<Vec<Card>>>, Vec<f32>)
 {
-    let mut log_probs = CardMatrix::new(unknown_cards.iter().cloned(),
-        state.player_count, -f32::INFINITY);
-    for _ in 0..10000 {
-        let hands = sample_hands_uniform(rng, rules, known_hands, unknown_cards);
-        let hands_log_prob = calc_hands_log_prob(rules, state, &hands);
-        for owner_i in 0..state.player_count {
-            let unknown_begin = 
-                if owner_i == 0 { 0 }
-                else { known_hands[owner_i].len() };
-            for &card in hands[owner_i][unknown_begin..].iter() {
-                let log_prob = log_probs.elem_mut(card, owner_i);
-                *log_prob = utils::log_add(*log_prob, hands_log_prob);
+    let (known_hands, unknown_cards) = split_cards(rules, state);
+    let mut samples = Vec::new();
+    let mut log_weights = Vec::new();
+    utils::run_batches(rng, thread_count, budget, |rng, iter_count| {
+        let mut unknown_cards = unknown_cards.to_vec();
+        (0..iter_count).map(|_| {
+            let hands = sample_hands_uniform(rng, rules, &known_hands, &mut unknown_cards);
+            let log_weight = calc_hands_log_prob(rules, policy, state, &hands);
+            (hands, log_weight)
+        }).collect::<Vec<_>>()
+    }, |thread_samples| {
+        for (hands, log_weight) in thread_samples.into_iter().flatten() {
+            samples.push(hands);
+            log_weights.push(log_weight);
+        }
+        false
+    });
+    (samples, log_weights)
+}
+
+/// The maximal number of deals that `Inference::Exact` is willing to enumerate.
+const MAX_EXACT_DEAL_COUNT: f32 = 1e6;
+
+/// Returns true if the unknown cards can be dealt in so few ways that the exact posterior
+/// can be enumerated.
+pub fn is_exact_tractable(rules: &Rules, state: &GameState) -> bool {
+    let (known_hands, unknown_cards) = split_cards(rules, state);
+    let mut card_count = unknown_cards.len();
+    let mut deal_count = 1.;
+    for known_hand in known_hands[1..].iter() {
+        let hand_len = rules.hand_len - known_hand.len();
+        // the number of ways to select `hand_len` cards from the `card_count` cards
+        deal_count *= (1..=hand_len)
+            .map(|i| (card_count - hand_len + i) as f32 / i as f32)
+            .product::<f32>();
+        card_count -= hand_len;
+    }
+    deal_count <= MAX_EXACT_DEAL_COUNT
+}
+
+/// Enumerates all deals of the `unknown_cards` to the other players and the deck. Returns
+/// the full hands of all owners, in the format of `sample_hands_uniform()`.
+pub fn enumerate_deals(rules: &Rules, known_hands: &[Vec<Card>], unknown_cards: &[Card])
+    -> Vec<Vec<Vec<Card>>>
+{
+    fn deal(
+        cards: &[Card], hand_lens: &[usize], hands: &mut Vec<Vec<Card>>,
+        deals: &mut Vec<Vec<Vec<Card>>>,
+    ) {
+        let (&card, cards) = match cards.split_first() {
+            Some(split) => split,
+            None => return deals.push(hands.clone()),
+        };
+        for owner_i in 0..hands.len() {
+            if hands[owner_i].len() < hand_lens[owner_i] {
+                hands[owner_i].push(card);
+                deal(cards, hand_lens, hands, deals);
+                hands[owner_i].pop();
             }
         }
     }
 
-    let mut probs = log_probs;
-    probs.for_each_row(utils::exp_normalize_log_pdf);
+    // the deck gets all cards that are not dealt to the players
+    let mut hand_lens = vec![rules.hand_len; known_hands.len()];
+    hand_lens[0] = unknown_cards.len() - known_hands[1..].iter()
+        .map(|hand| rules.hand_len - hand.len())
+        .sum::<usize>();
+    let mut deals = Vec::new();
+    deal(unknown_cards, &hand_lens, &mut known_hands.to_vec(), &mut deals);
+    deals
+}
+
+/// Splits the cards into the cards that are known to be in the hand of a given owner (the
+/// cards that other players have already played) and the sorted cards with unknown owner.
+#[allow(clippy::needless_range_loop)]
+pub fn split_cards(rules: &Rules, state: &GameState) -> (Vec<Vec<Card>>, Vec<Card>) {
+    let mut known_hands = vec![Vec::new(); state.player_count];
+    let mut unknown_cards = rules.cards().collect::<HashSet<Card>>();
+    for round in state.observed_rounds() {
+        for player_i in 1..state.player_count {
+            let card = round.actions[player_i];
+            known_hands[player_i].push(card);
+            unknown_cards.remove(&card);
+        }
+    }
+    // my played cards may have been eaten in the same round, so they are not necessarily
+    // on any table
+    state.past_rounds.iter()
+        .flat_map(|r| r.table.cards())
+        .chain(state.table.cards())
+        .chain(state.my_hand.iter())
+        .chain(state.observed_rounds().map(|round| &round.actions[0]))
+        .for_each(|card| { unknown_cards.remove(card); });
+
+    // convert the `unknown_cards` set into a vec and sort it to get a deterministic
+    // result (without sorting the order depends on the HashSet randomization).
+    let mut unknown_cards = unknown_cards.into_iter().collect::<Vec<Card>>();
+    unknown_cards.sort_unstable_by_key(|&card| card.idx());
+    (known_hands, unknown_cards)
+}
+
+/// Computes the probabilities of owners of every card from weighted samples of hands of
+/// all owners.
+fn count_probs(
+    cards: &[Card], player_count: usize,
+    samples: &[Vec<Vec<Card>>], weights: &[f32],
+) -> CardMatrix<f32>
+{
+    let mut probs = CardMatrix::new(cards.iter().cloned(), player_count, 0.);
+    for (hands, weight) in samples.iter().zip(weights.iter()) {
+        for (owner_i, hand) in hands.iter().enumerate() {
+            for &card in hand.iter() {
+                *probs.elem_mut(card, owner_i) += weight;
+            }
+        }
+    }
+    probs.for_each_row(utils::normalize_pdf);
     probs
 }
 
-/// Uniformly samples full hands for all players (except us).
-fn sample_hands_uniform(
+/// Strips the known cards (the cards that were already played) from the full hands.
+fn remaining_hands(known_hands: &[Vec<Card>], mut hands: Vec<Vec<Card>>) -> Vec<Vec<Card>> {
+    for (hand, known_hand) in hands.iter_mut().zip(known_hands.iter()).skip(1) {
+        hand.drain(..known_hand.len());
+    }
+    hands
+}
+
+/// Prepends the known cards to the remaining hands (the inverse of `remaining_hands()`).
+fn full_hands(known_hands: &[Vec<Card>], hands: &[Vec<Card>]) -> Vec<Vec<Card>> {
+    known_hands.iter().zip(hands.iter())
+        .map(|(known_hand, hand)| known_hand.iter().chain(hand.iter()).cloned().collect())
+        .collect()
+}
+
+/// Uniformly samples full hands for all players (except us). The remaining cards are put
+/// into the deck, which stays empty when all cards are dealt.
+#[allow(clippy::needless_range_loop)]
+pub fn sample_hands_uniform(
     rng: &mut dyn RngCore, rules: &Rules,
     known_hands: &[Vec<Card>], unknown_cards: &mut [Card]
 ) -> Vec<Vec<Card>>
@@ -161,18 +531,263 @@ fn sample_hands_uniform(
     hands
 }
 
-/// Calculates the log-probability log Pr(state | hands, policy_1()) that the players
-/// would play as they did if they had the given hands and followed the policy_1(). 
-fn calc_hands_log_prob(rules: &Rules, state: &GameState, hands: &[Vec<Card>]) -> f32 {
+/// Calculates the log-probability log Pr(state | hands, policy) that the players would
+/// play as they did if they had the given hands and followed the `policy`. The recorded
+/// row choices are included with their probability under the `policy`.
+pub fn calc_hands_log_prob(
+    rules: &Rules, policy: &dyn Policy,
+    state: &GameState, hands: &[Vec<Card>],
+) -> f32
+{
+    let round_count = state.observed_rounds().count();
+    (1..hands.len())
+        .map(|player_i| calc_player_log_prob(rules, policy, state, round_count,
+            player_i, &hands[player_i]))
+        .sum()
+}
+
+/// Calculates the part of `calc_hands_log_prob()` that belongs to a single player with the
+/// given full hand (the played cards followed by the cards that remain in the hand), using
+/// only the first `round_count` observed rounds.
+pub fn calc_player_log_prob(
+    rules: &Rules, policy: &dyn Policy, state: &GameState, round_count: usize,
+    player_i: usize, hand: &[Card],
+) -> f32
+{
     let mut log_prob = 0.;
-    for (round_i, round) in state.past_rounds.iter().enumerate() {
-        for player_i in 1..hands.len() {
-            assert_eq!(hands[player_i][round_i], round.actions[player_i]);
-            let hand = &hands[player_i][round_i..];
-            let policy = policy::policy_1(rules, &round.table, state.player_count, hand);
-            log_prob += policy[0].ln();
+    for_each_choice(rules, policy, state, round_count, player_i, hand, |choice| {
+        log_prob += match choice {
+            Choice::Action { probs, .. } => probs[0].ln(),
+            Choice::Row { probs, row_i } => probs[row_i].ln(),
+        };
+    });
+    log_prob
+}
+
+/// A choice that a player made in an observed round, with the probabilities of all options
+/// under a policy.
+pub enum Choice<'a> {
+    /// The player played the first card of the `hand`.
+    Action { hand: &'a [Card], probs: Vec<f32> },
+    /// The player ate the row `row_i` (see `Round::row_choices`).
+    Row { row_i: usize, probs: Vec<f32> },
+}
+
+/// Calls `f()` with every choice that the player made in the first `round_count` observed
+/// rounds, if she had the given full hand and followed the `policy`.
+pub fn for_each_choice<F>(
+    rules: &Rules, policy: &dyn Policy, state: &GameState, round_count: usize,
+    player_i: usize, hand: &[Card], mut f: F,
+) where F: FnMut(Choice)
+{
+    for (round_i, round) in state.observed_rounds().take(round_count).enumerate() {
+        assert_eq!(hand[round_i], round.actions[player_i]);
+        let probs = policy.action_probs(rules, &round.table, state.player_count,
+            player_i, &hand[round_i..]);
+        f(Choice::Action { hand: &hand[round_i..], probs });
+
+        if round.row_choices[player_i].is_some() {
+            let mut engine = GameEngine::new(rules, round.table.clone());
+            engine.resolve_round(&round.actions, |table, chooser_i, card| {
+                let row_i = round.row_choices[chooser_i].unwrap_or(0);
+                if chooser_i == player_i {
+                    let probs = policy.row_probs(rules, table, state.player_count,
+                        player_i, card, &hand[round_i + 1..]);
+                    f(Choice::Row { row_i, probs });
+                }
+                row_i
+            });
         }
     }
-    log_prob
 }
 
+#[cfg(test)]
+mod test {
+    use rand_pcg::{Pcg64Mcg};
+    use crate::game::{Variant};
+    use crate::policy::{Policy1, RandomPolicy};
+    use crate::test_utils::{
+        GameBuilder, deal_distance, exact_deal_probs, make_cards, make_table, small_rules,
+    };
+    use super::*;
+
+    #[test]
+    fn test_tactical_endgame() {
+        // the deck has 2 * 3 + 2 = 8 cards, I had 2 6 8 and the opponent had 3 4 7
+        let rules = small_rules(Variant::Tactical, 8, 5, 3, 2);
+        let state = GameBuilder::new(&rules, 2, &[&[1], &[5]])
+            .round(&[2, 3])
+            .round(&[6, 4])
+            .state(&[8]);
+
+        let mut rng = Pcg64Mcg::new(42);
+        let budget = SearchBudget { iterations: 10000, time: None };
+        let distrib = HandsDistrib::estimate(&mut rng, &rules, &Policy1::default(), &state,
+            Inference::Importance, 2, budget);
+        assert_eq!(*distrib.card_probs.elem(rules.card(7), 0), 0.);
+        assert_eq!(*distrib.card_probs.elem(rules.card(7), 1), 1.);
+        for _ in 0..10 {
+            let (hands, weight) = distrib.sample(&mut rng, &rules);
+            assert_eq!(hands, vec![Vec::new(), make_cards(&rules, &[7])]);
+            assert_eq!(weight, 1.);
+        }
+    }
+
+    #[test]
+    fn test_joint_sampling() {
+        // all cards are dealt: the initial table was 3 | 8, I had 1 4 9 13 and the
+        // opponents played 11 and 6, so the unknown cards 2 5 7 10 12 14 are split between
+        // the opponents and the hand of one opponent determines the hand of the other. The
+        // rows are short, so the played cards say something about the rest of the hands.
+        let rules = small_rules(Variant::Tactical, 14, 2, 4, 2);
+        let state = GameBuilder::new(&rules, 3, &[&[3], &[8]])
+            .round(&[4, 11, 6])
+            .state(&[1, 9, 13]);
+
+        let (known_hands, unknown_cards) = split_cards(&rules, &state);
+        assert_eq!(unknown_cards, make_cards(&rules, &[2, 5, 7, 10, 12, 14]));
+        let exact_probs = exact_deal_probs(&rules, &Policy1::default(), &state);
+        assert_eq!(exact_probs.len(), 20);
+
+        let mut rng = Pcg64Mcg::new(42);
+        let budget = SearchBudget { iterations: 50000, time: None };
+        let mut distrib = HandsDistrib::estimate(&mut rng, &rules, &Policy1::default(), &state,
+            Inference::Importance, 2, budget);
+        let sampling_distance = |rng: &mut Pcg64Mcg, distrib: &HandsDistrib| {
+            deal_distance(&exact_probs, (0..100000).map(|_| {
+                let (hands, weight) = distrib.sample(rng, &rules);
+                (full_hands(&known_hands, &hands), weight)
+            }))
+        };
+        let joint_distance = sampling_distance(&mut rng, &distrib);
+        distrib.set_sampling(&mut rng, Sampling::Resampled);
+        let resampled_distance = sampling_distance(&mut rng, &distrib);
+        distrib.set_sampling(&mut rng, Sampling::Marginal);
+        let marginal_distance = sampling_distance(&mut rng, &distrib);
+
+        // the marginal sampling ignores that the hands of the opponents are complementary,
+        // which costs it more than the sampling noise of the joint samples
+        assert!(joint_distance < 0.015);
+        assert!(resampled_distance < 0.015);
+        assert!(marginal_distance > joint_distance + 0.015);
+    }
+
+    #[test]
+    fn test_split_eaten_cards() {
+        // my 2 extends the row 1 to its maximal length and the 3 of the opponent eats it,
+        // so my 2 is neither on the table nor in my hand
+        let rules = small_rules(Variant::Standard, 10, 2, 2, 2);
+        let state = GameBuilder::new(&rules, 2, &[&[1], &[5]])
+            .round(&[2, 3])
+            .state(&[9]);
+        assert_eq!(state.table, make_table(&rules, &[&[3], &[5]]));
+
+        let (known_hands, unknown_cards) = split_cards(&rules, &state);
+        assert_eq!(known_hands, vec![vec![], make_cards(&rules, &[3])]);
+        assert_eq!(unknown_cards, make_cards(&rules, &[4, 6, 7, 8, 10]));
+    }
+
+    #[test]
+    fn test_reweight() {
+        // reweighting the exact posterior under the random policy to `Policy1` gives the
+        // exact posterior under `Policy1`
+        let rules = small_rules(Variant::Standard, 20, 5, 4, 2);
+        let state = GameBuilder::new(&rules, 3, &[&[5], &[15]])
+            .round(&[7, 16, 9])
+            .round(&[12, 18, 10])
+            .state(&[19, 20]);
+
+        let exact = HandsDistrib::exact(&rules, &Policy1::default(), &state);
+        let reweighted = HandsDistrib::exact(&rules, &RandomPolicy, &state)
+            .reweight(&rules, &RandomPolicy, &Policy1::default(), &state);
+        assert_eq!(reweighted.samples, exact.samples);
+        for (&weight, &exact_weight) in reweighted.weights.iter().zip(exact.weights.iter()) {
+            assert!((weight - exact_weight).abs() < 1e-3 * exact_weight.max(1.));
+        }
+    }
+
+    #[test]
+    fn test_exact_inference() {
+        // a deck of 20 cards, 3 players with 4 cards; after two rounds, the opponents have
+        // 2 unknown cards each and the remaining 6 unknown cards are in the deck
+        let rules = small_rules(Variant::Standard, 20, 5, 4, 2);
+        let state = GameBuilder::new(&rules, 3, &[&[5], &[15]])
+            .round(&[7, 16, 9])
+            .round(&[12, 18, 10])
+            .state(&[19, 20]);
+        assert!(is_exact_tractable(&rules, &state));
+
+        let exact = HandsDistrib::exact(&rules, &Policy1::default(), &state);
+        // the 10 unknown cards can be dealt in (10 choose 2) * (8 choose 2) ways
+        assert_eq!(exact.samples.len(), 45 * 28);
+
+        let mut rng = Pcg64Mcg::new(42);
+        let budget = SearchBudget { iterations: 20000, time: None };
+        for &inference in [Inference::Importance, Inference::Mcmc, Inference::Smc].iter() {
+            let distrib = HandsDistrib::estimate(&mut rng, &rules, &Policy1::default(), &state,
+                inference, 2, budget);
+
+            // the probabilities of owners computed from the samples...
+            let mut sample_probs = CardMatrix::new(distrib.cards.iter().cloned(),
+                state.player_count, 0.);
+            for _ in 0..20000 {
+                let (hands, weight) = distrib.sample(&mut rng, &rules);
+                for (owner_i, hand) in hands.iter().enumerate() {
+                    for &card in hand.iter() {
+                        *sample_probs.elem_mut(card, owner_i) += weight;
+                    }
+                }
+            }
+            sample_probs.for_each_row(utils::normalize_pdf);
+
+            // ...and in the distribution must be close to the exact probabilities
+            for &card in exact.cards.iter() {
+                for owner_i in 0..state.player_count {
+                    let exact_prob = *exact.card_probs.elem(card, owner_i);
+                    let prob = *distrib.card_probs.elem(card, owner_i);
+                    let sample_prob = *sample_probs.elem(card, owner_i);
+                    assert!((prob - exact_prob).abs() < 0.04, "{}: {:?} {}: {} vs {}",
+                        inference.name(), card, owner_i, prob, exact_prob);
+                    assert!((sample_prob - exact_prob).abs() < 0.04, "{}: {:?} {}: {} vs {}",
+                        inference.name(), card, owner_i, sample_prob, exact_prob);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let rules = small_rules(Variant::Standard, 20, 5, 4, 2);
+        let state = GameBuilder::new(&rules, 3, &[&[5], &[15]])
+            .round(&[7, 16, 9])
+            .round(&[12, 18, 10])
+            .state(&[19, 20]);
+        let mut rng = Pcg64Mcg::new(42);
+
+        // the exact posteriors under the random policy (uniform) and under `Policy1` are
+        // reliable
+        for policy in [&RandomPolicy as &dyn Policy, &Policy1::default()].iter() {
+            let diagnostics = HandsDistrib::exact(&rules, *policy, &state)
+                .diagnose(&mut rng, &rules, 1000);
+            assert!(!diagnostics.is_degenerate(), "{}", diagnostics);
+        }
+
+        // three of the importance weights dominate all others
+        let (known_hands, unknown_cards) = split_cards(&rules, &state);
+        let full_samples = enumerate_deals(&rules, &known_hands, &unknown_cards);
+        let log_weights = (0..full_samples.len())
+            .map(|sample_i| if sample_i < 3 { 0. } else { -20. })
+            .collect::<Vec<_>>();
+        let mut distrib = HandsDistrib::from_samples(&rules, &state, full_samples,
+            &log_weights, None);
+        let mut estimate_weights = LogWeightStats::new();
+        log_weights.iter().for_each(|&log_weight| estimate_weights.add(log_weight));
+        distrib.estimate_weights = Some(estimate_weights);
+
+        let diagnostics = distrib.diagnose(&mut rng, &rules, 1000);
+        let estimate_weights = diagnostics.estimate_weights.as_ref().unwrap();
+        assert!((estimate_weights.effective_sample_size() - 3.).abs() < 0.1);
+        assert!((estimate_weights.max_weight_fraction() - 1. / 3.).abs() < 0.01);
+        assert!(diagnostics.is_degenerate(), "{}", diagnostics);
+    }
+}
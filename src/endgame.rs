@@ -0,0 +1,197 @@
+use crate::game::{Card, GameEngine, Rules, Table};
+use crate::policy::{Policy};
+
+/// The maximal number of joint actions that we are willing to enumerate in the solver.
+const MAX_LEAF_COUNT: f64 = 10000.;
+
+/// Returns true if the endgame with the given hands is small enough for the exact solver:
+/// the hands must have at most `max_hand_len` cards and the number of joint actions of all
+/// players in all remaining rounds must be reasonably small.
+pub fn is_tractable(hands: &[Vec<Card>], max_hand_len: usize) -> bool {
+    let hand_len = hands[0].len();
+    if hand_len > max_hand_len {
+        return false
+    }
+    let leaf_count = (1..=hand_len)
+        .map(|len| (len as f64).powi(hands.len() as i32))
+        .product::<f64>();
+    leaf_count <= MAX_LEAF_COUNT
+}
+
+/// Computes the exact expected value of `eval_costs()` at the end of the deal for every
+/// action of mine (cards in `hands[0]`), when all hands are known. After the first action,
+/// I play to minimize the value, while the other players follow the `policy`. `costs` are
+/// the costs that the players have already collected.
+///
+/// Note that the solver chooses my later actions and rows with the knowledge of all hands,
+/// whereas in the playouts of `mc` my later actions follow the `policy`. The values of the
+/// endgame are thus optimistic for me, but the difference is limited to the last few
+/// rounds, in which the hands of other players are mostly determined by the played cards.
+pub fn action_values(
+    rules: &Rules, policy: &dyn Policy, table: &Table, hands: &[Vec<Card>],
+    costs: &[f32], eval_costs: &dyn Fn(&[f32]) -> f32,
+) -> Vec<f32>
+{
+    let solver = Solver { rules, policy, eval_costs };
+    let policies = solver.policies(table, hands);
+    (0..hands[0].len())
+        .map(|my_action_i| solver.action_value(table, hands, costs, &policies, my_action_i))
+        .collect()
+}
+
+/// Computes the exact expected value of `eval_costs()` at the end of the deal, when I play
+/// to minimize it (see `action_values()`).
+pub fn value(
+    rules: &Rules, policy: &dyn Policy, table: &Table, hands: &[Vec<Card>],
+    costs: &[f32], eval_costs: &dyn Fn(&[f32]) -> f32,
+) -> f32
+{
+    let solver = Solver { rules, policy, eval_costs };
+    solver.value(table, hands, costs)
+}
+
+struct Solver<'a> {
+    rules: &'a Rules,
+    policy: &'a dyn Policy,
+    eval_costs: &'a dyn Fn(&[f32]) -> f32,
+}
+
+impl<'a> Solver<'a> {
+    fn value(&self, table: &Table, hands: &[Vec<Card>], costs: &[f32]) -> f32 {
+        if hands[0].is_empty() {
+            return (self.eval_costs)(costs)
+        }
+        let policies = self.policies(table, hands);
+        (0..hands[0].len())
+            .map(|my_action_i| self.action_value(table, hands, costs, &policies, my_action_i))
+            .fold(f32::INFINITY, f32::min)
+    }
+
+    /// Computes the policies of all players except me (`policies[0]` is empty).
+    fn policies(&self, table: &Table, hands: &[Vec<Card>]) -> Vec<Vec<f32>> {
+        hands.iter().enumerate()
+            .map(|(player_i, hand)| {
+                if player_i == 0 { Vec::new() }
+                else if hand.len() == 1 { vec![1.] }
+                else { self.policy.action_probs(self.rules, table, hands.len(), player_i, hand) }
+            })
+            .collect()
+    }
+
+    /// Computes the expected value when I play `hands[0][my_action_i]` in this round.
+    fn action_value(
+        &self, table: &Table, hands: &[Vec<Card>],
+        costs: &[f32], policies: &[Vec<f32>], my_action_i: usize,
+    ) -> f32
+    {
+        // enumerate all joint actions of other players, `action_is` works as an odometer
+        let player_count = hands.len();
+        let mut value = 0.;
+        let mut action_is = vec![0; player_count];
+        action_is[0] = my_action_i;
+        loop {
+            let prob = action_is.iter().enumerate().skip(1)
+                .map(|(player_i, &action_i)| policies[player_i][action_i])
+                .product::<f32>();
+            if prob > 0. {
+                value += prob * self.round_value(table, hands, costs, &action_is);
+            }
+
+            let mut player_i = 1;
+            loop {
+                if player_i == player_count {
+                    return value
+                }
+                action_is[player_i] += 1;
+                if action_is[player_i] < hands[player_i].len() {
+                    break
+                }
+                action_is[player_i] = 0;
+                player_i += 1;
+            }
+        }
+    }
+
+    /// Computes the expected value when the players play `hands[player_i][action_is[player_i]]`
+    /// in this round.
+    fn round_value(
+        &self, table: &Table, hands: &[Vec<Card>],
+        costs: &[f32], action_is: &[usize],
+    ) -> f32
+    {
+        let mut next_hands = hands.to_vec();
+        let actions = next_hands.iter_mut().zip(action_is.iter())
+            .map(|(hand, &action_i)| hand.remove(action_i))
+            .collect::<Vec<_>>();
+
+        // only the player with the lowest card may need to choose a row, because all other
+        // cards are higher than the chosen row
+        let (chooser_i, &lowest_card) = actions.iter().enumerate()
+            .min_by_key(|(_, card)| card.idx()).unwrap();
+        if table.match_row(lowest_card).is_some() {
+            return self.next_round_value(table, &next_hands, costs, &actions, 0)
+        }
+
+        let row_values = (0..self.rules.row_count)
+            .map(|row_i| self.next_round_value(table, &next_hands, costs, &actions, row_i));
+        if chooser_i == 0 {
+            row_values.fold(f32::INFINITY, f32::min)
+        } else {
+            let row_policy = self.policy.row_probs(self.rules, table, hands.len(),
+                chooser_i, lowest_card, &next_hands[chooser_i]);
+            row_values.zip(row_policy.iter()).map(|(value, prob)| value * prob).sum()
+        }
+    }
+
+    fn next_round_value(
+        &self, table: &Table, next_hands: &[Vec<Card>],
+        costs: &[f32], actions: &[Card], row_i: usize,
+    ) -> f32
+    {
+        let mut engine = GameEngine::new(self.rules, table.clone());
+        let outcome = engine.resolve_round(actions, |_, _, _| row_i);
+        let next_costs = costs.iter().zip(outcome.costs())
+            .map(|(cost, round_cost)| cost + round_cost as f32)
+            .collect::<Vec<_>>();
+        self.value(engine.table(), next_hands, &next_costs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::game::{Variant};
+    use crate::policy::{Policy1, RandomPolicy};
+    use crate::test_utils::{make_cards, make_table, small_rules};
+    use super::*;
+
+    #[test]
+    fn test_action_values() {
+        let rules = small_rules(Variant::Standard, 50, 5, 2, 2);
+        // the rows cost 3 and 4
+        let table = make_table(&rules, &[&[10], &[20, 21]]);
+        let my_cost = |costs: &[f32]| costs[0];
+        let my_rel_cost = |costs: &[f32]| costs[0] - costs[1];
+
+        // in the last round, I take the cheaper row with my card 5
+        let policy = Policy1::default();
+        let hands = vec![make_cards(&rules, &[5]), make_cards(&rules, &[30])];
+        assert_eq!(action_values(&rules, &policy, &table, &hands, &[0., 0.], &my_cost), vec![3.]);
+        assert_eq!(value(&rules, &policy, &table, &hands, &[1., 2.], &my_cost), 4.);
+
+        // the opponent eats one of the rows with her card 5, each with probability 1/2
+        let hands = vec![make_cards(&rules, &[22]), make_cards(&rules, &[5])];
+        assert_eq!(action_values(&rules, &RandomPolicy, &table, &hands, &[0., 0.], &my_rel_cost),
+            vec![-3.5]);
+
+        // the opponent plays 30 or 11 in the first round, each with probability 1/2:
+        // - if I play 5, I eat the row 10 (cost 3) and then play 22 safely in both cases
+        // - if I play 22 and the opponent plays 30, the row 20 21 22 30 costs 12, so I eat
+        //   the row 10 (cost 3) with my 5 in the second round
+        // - if I play 22 and the opponent plays 11, the table is 10 11 and 20 21 22 in the
+        //   second round and I eat the cheaper row 10 11 (cost 8) with my 5
+        let hands = vec![make_cards(&rules, &[5, 22]), make_cards(&rules, &[30, 11])];
+        let values = action_values(&rules, &RandomPolicy, &table, &hands, &[0., 0.], &my_cost);
+        assert_eq!(values, vec![3., 0.5 * 3. + 0.5 * 8.]);
+        assert_eq!(value(&rules, &RandomPolicy, &table, &hands, &[0., 0.], &my_cost), 3.);
+    }
+}
@@ -0,0 +1,247 @@
+use rand::{RngCore};
+
+use crate::utils;
+use crate::distrib::{self, Choice, HandsDistrib};
+use crate::game::{GameState, Rules};
+use crate::policy::{PlayerModel, Policy};
+use crate::profile::{Profile};
+
+/// The temperatures and card biases of the models that we consider for every player.
+const TEMPERATURES: [f32; 7] = [0.5, 0.7, 0.85, 1., 1.2, 1.5, 2.];
+const CARD_BIASES: [f32; 9] = [-4., -3., -2., -1., 0., 1., 2., 3., 4.];
+
+/// The standard deviations of the Gaussian priors on the logarithm of the temperature and
+/// on the card bias. The priors keep the models close to the base policy when a player
+/// has played only a few cards.
+const LOG_TEMPERATURE_SD: f32 = 0.5;
+const CARD_BIAS_SD: f32 = 2.;
+
+/// The minimal number of decisions (in this game and in the profile) before we adjust the
+/// model of a player. A single surprising card would otherwise push the model of a player
+/// whom we have just met to the edge of the grid.
+const MIN_DECISION_COUNT: usize = 3;
+
+/// Fits a `PlayerModel` of every other player to the cards that she played and the rows
+/// that she ate, so that the `policy` adjusted by the model explains her choices. We don't
+/// know the hands from which the players chose, so we average the likelihood over
+/// `sample_count` samples from `distrib` (which must be estimated with the same `policy`)
+/// and pick the model with the maximal posterior probability. The decisions of the players
+/// in previous games (`profiles`, indexed by players) are included in the prior. The model
+/// of player 0 (me) is always neutral, and so is the model of a player who has made fewer
+/// than `MIN_DECISION_COUNT` decisions.
+pub fn fit_models(
+    rng: &mut dyn RngCore, rules: &Rules, policy: &dyn Policy,
+    state: &GameState, distrib: &HandsDistrib, profiles: &[Profile], sample_count: usize,
+) -> Vec<PlayerModel>
+{
+    let models = grid_models();
+
+    // the samples come from the posterior under the base `policy`, so the likelihood of a
+    // model relative to the base policy is the weighted mean of the likelihood ratios of
+    // the samples; `log_ratios[player_i][model_i]` collects the log-ratios of all samples
+    let round_count = state.observed_rounds().count();
+    let (known_hands, _) = distrib::split_cards(rules, state);
+    let mut log_ratios = vec![vec![Vec::with_capacity(sample_count); models.len()];
+        state.player_count];
+    let mut decision_counts = vec![0; state.player_count];
+    for _ in 0..sample_count {
+        let (hands, weight) = distrib.sample(rng, rules);
+        if weight == 0. {
+            continue
+        }
+        for player_i in 1..state.player_count {
+            let hand = known_hands[player_i].iter().chain(hands[player_i].iter())
+                .cloned().collect::<Vec<_>>();
+            let mut base_log_prob = 0.;
+            let mut log_probs = vec![0.; models.len()];
+            let mut decision_count = 0;
+            distrib::for_each_choice(rules, policy, state, round_count, player_i, &hand,
+                |choice| match choice {
+                    Choice::Action { hand, probs } => {
+                        decision_count += 1;
+                        base_log_prob += probs[0].ln();
+                        for (log_prob, model) in log_probs.iter_mut().zip(models.iter()) {
+                            let mut probs = probs.clone();
+                            model.adjust_action_probs(rules, hand, &mut probs);
+                            *log_prob += probs[0].ln();
+                        }
+                    },
+                    Choice::Row { row_i, probs } => {
+                        decision_count += 1;
+                        base_log_prob += probs[row_i].ln();
+                        for (log_prob, model) in log_probs.iter_mut().zip(models.iter()) {
+                            let mut probs = probs.clone();
+                            model.adjust_row_probs(&mut probs);
+                            *log_prob += probs[row_i].ln();
+                        }
+                    },
+                });
+            decision_counts[player_i] = decision_count;
+
+            for (model_i, log_prob) in log_probs.into_iter().enumerate() {
+                log_ratios[player_i][model_i].push(weight.ln() + log_prob - base_log_prob);
+            }
+        }
+    }
+
+    let mut fitted_models = vec![PlayerModel::NEUTRAL; state.player_count];
+    for player_i in 1..state.player_count {
+        if decision_counts[player_i] + profiles[player_i].decision_count() < MIN_DECISION_COUNT {
+            continue
+        }
+        let log_posteriors = log_ratios[player_i].iter()
+            .zip(models.iter())
+            .map(|(log_ratios, model)| {
+                let log_likelihood = log_ratios.iter().cloned()
+                    .fold(-f32::INFINITY, utils::log_add);
+                log_likelihood + log_prior(model, &profiles[player_i])
+            })
+            .collect::<Vec<_>>();
+        fitted_models[player_i] = models[best_i(&log_posteriors)];
+    }
+    fitted_models
+}
+
+/// Fits the models of players only to their decisions in previous games (see
+/// `fit_models()`).
+pub fn fit_profile_models(profiles: &[Profile]) -> Vec<PlayerModel> {
+    let models = grid_models();
+    let mut fitted_models = vec![PlayerModel::NEUTRAL; profiles.len()];
+    for player_i in 1..profiles.len() {
+        if profiles[player_i].decision_count() < MIN_DECISION_COUNT {
+            continue
+        }
+        let log_posteriors = models.iter()
+            .map(|model| log_prior(model, &profiles[player_i]))
+            .collect::<Vec<_>>();
+        fitted_models[player_i] = models[best_i(&log_posteriors)];
+    }
+    fitted_models
+}
+
+fn grid_models() -> Vec<PlayerModel> {
+    TEMPERATURES.iter()
+        .flat_map(|&temperature| CARD_BIASES.iter()
+            .map(move |&card_bias| PlayerModel { temperature, card_bias }))
+        .collect()
+}
+
+/// Returns the log-probability of the `model` before we observe the current game.
+fn log_prior(model: &PlayerModel, profile: &Profile) -> f32 {
+    let log_temperature = model.temperature.ln() / LOG_TEMPERATURE_SD;
+    let card_bias = model.card_bias / CARD_BIAS_SD;
+    -0.5 * (log_temperature * log_temperature + card_bias * card_bias)
+        + profile.log_likelihood(model)
+}
+
+fn best_i(log_posteriors: &[f32]) -> usize {
+    (0..log_posteriors.len())
+        .max_by(|&i, &j| log_posteriors[i].partial_cmp(&log_posteriors[j]).unwrap())
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use rand_pcg::{Pcg64Mcg};
+    use crate::game::{Variant};
+    use crate::policy::{Policy1, RandomPolicy};
+    use crate::test_utils::{GameBuilder, small_rules};
+    use super::*;
+
+    #[test]
+    fn test_fit_card_bias() {
+        // player 1 dumps her highest cards and player 2 her lowest cards, while the single
+        // unknown card in their hands is somewhere in the middle
+        let rules = small_rules(Variant::Standard, 20, 5, 4, 2);
+        let state = GameBuilder::new(&rules, 3, &[&[1], &[10]])
+            .round(&[12, 20, 2])
+            .round(&[13, 19, 3])
+            .round(&[14, 18, 4])
+            .state(&[15]);
+
+        let mut rng = Pcg64Mcg::new(42);
+        let distrib = HandsDistrib::exact(&rules, &RandomPolicy, &state);
+        let models = fit_models(&mut rng, &rules, &RandomPolicy, &state, &distrib,
+            &vec![Profile::default(); 3], 1000);
+        assert_eq!(models[0], PlayerModel::NEUTRAL);
+        assert!(models[1].card_bias > 0.);
+        assert!(models[2].card_bias < 0.);
+    }
+
+    #[test]
+    fn test_fit_temperature() {
+        // player 1 always plays the most likely card under the policy, while player 2
+        // always plays the least likely card (such as her 18 in the third round); one card
+        // of each is unknown
+        let rules = small_rules(Variant::Tactical, 20, 5, 6, 2);
+        let state = GameBuilder::new(&rules, 3, &[&[3], &[11]])
+            .round(&[20, 19, 5])
+            .round(&[17, 15, 7])
+            .round(&[13, 12, 18])
+            .round(&[9, 8, 10])
+            .round(&[4, 6, 16])
+            .state(&[1]);
+
+        let mut rng = Pcg64Mcg::new(42);
+        let distrib = HandsDistrib::exact(&rules, &Policy1::default(), &state);
+        let models = fit_models(&mut rng, &rules, &Policy1::default(), &state, &distrib,
+            &vec![Profile::default(); 3], 1000);
+        assert_eq!(models[0], PlayerModel::NEUTRAL);
+        assert!(models[1].temperature < 1.);
+        assert!(models[2].temperature > 1.);
+    }
+
+    #[test]
+    fn test_fit_little_data() {
+        // after a single round, player 1 has eaten a full row with her 4 and player 2 has
+        // played the highest card, but that is too little to adjust their models
+        let rules = small_rules(Variant::Standard, 20, 3, 4, 2);
+        let state = GameBuilder::new(&rules, 3, &[&[1, 2, 3], &[10]])
+            .round(&[11, 4, 20])
+            .state(&[5, 12, 16]);
+
+        let mut rng = Pcg64Mcg::new(42);
+        let distrib = HandsDistrib::exact(&rules, &Policy1::default(), &state);
+        let models = fit_models(&mut rng, &rules, &Policy1::default(), &state, &distrib,
+            &vec![Profile::default(); 3], 1000);
+        assert_eq!(models, vec![PlayerModel::NEUTRAL; 3]);
+    }
+
+    #[test]
+    fn test_fit_profile_models() {
+        // player 1 plays her cards from the highest to the lowest in every game, so her
+        // card bias grows with every recorded game
+        let rules = small_rules(Variant::Standard, 20, 5, 4, 2);
+        let games = [
+            GameBuilder::new(&rules, 3, &[&[5], &[15]])
+                .round(&[8, 20, 2])
+                .round(&[12, 17, 14])
+                .round(&[1, 11, 9])
+                .round(&[19, 3, 18])
+                .state(&[]),
+            GameBuilder::new(&rules, 3, &[&[10], &[4]])
+                .round(&[15, 18, 3])
+                .round(&[6, 13, 16])
+                .round(&[20, 7, 9])
+                .round(&[1, 2, 12])
+                .state(&[]),
+            GameBuilder::new(&rules, 3, &[&[8], &[14]])
+                .round(&[12, 19, 7])
+                .round(&[2, 16, 20])
+                .round(&[17, 10, 1])
+                .round(&[4, 5, 11])
+                .state(&[]),
+        ];
+
+        let mut profiles = vec![Profile::default(); 2];
+        assert_eq!(fit_profile_models(&profiles), vec![PlayerModel::NEUTRAL; 2]);
+        let mut card_bias = 0.;
+        for state in games.iter() {
+            assert!(profiles[1].add_game(&rules, &Policy1::default(), state, 1));
+            let models = fit_profile_models(&profiles);
+            assert_eq!(models[0], PlayerModel::NEUTRAL);
+            assert!(models[1].card_bias > card_bias);
+            card_bias = models[1].card_bias;
+        }
+    }
+}
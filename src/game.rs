@@ -7,27 +7,14 @@ pub struct Card {
 }
 
 impl Card {
-    pub fn new(idx: usize) -> Card {
-        let mut cost = 0;
-        if idx % 5 == 0 {
-            cost += 2;
-            if idx % 2 == 0 {
-                cost += 1;
-            }
-        }
-        if idx % 11 == 0 {
-            cost += 5;
-        }
-        Card { idx_u8: idx as u8, cost_u8: cmp::max(cost, 1) }
+    /// Creates a card with the given cost (use `Rules::card()` to get the cost from the
+    /// rules).
+    pub fn new(idx: usize, cost: usize) -> Card {
+        Card { idx_u8: idx as u8, cost_u8: cost as u8 }
     }
 
     pub fn idx(&self) -> usize { self.idx_u8 as usize }
     pub fn cost(&self) -> usize { self.cost_u8 as usize }
-
-    pub fn mean_cost(begin_idx: usize, end_idx: usize) -> f32 {
-        let count = (end_idx - begin_idx) as f32;
-        count * (2./5. + 1./10. + 5./11. + 1.)
-    }
 }
 
 impl fmt::Debug for Card {
@@ -36,7 +23,73 @@ impl fmt::Debug for Card {
     }
 }
 
-#[derive(Debug, Clone)]
+/// The costs (number of "bullheads") of cards, indexed by card index.
+#[derive(Clone)]
+pub struct CostTable {
+    costs: Vec<u8>,
+    /// `cost_sums[idx]` is the sum of costs of cards with index lower than `idx`.
+    cost_sums: Vec<usize>,
+}
+
+impl CostTable {
+    /// The costs in the standard game: 2 for multiples of 5, 3 for multiples of 10, 5 for
+    /// multiples of 11 (7 for 55) and 1 for all other cards.
+    pub fn standard() -> CostTable {
+        let costs = (0..=u8::MAX as usize).map(|idx| {
+            let mut cost = 0;
+            if idx % 5 == 0 {
+                cost += 2;
+                if idx % 2 == 0 {
+                    cost += 1;
+                }
+            }
+            if idx % 11 == 0 {
+                cost += 5;
+            }
+            cmp::max(cost, 1)
+        }).collect();
+        CostTable::from_costs(costs)
+    }
+
+    /// All cards have the same cost.
+    pub fn flat(cost: usize) -> CostTable {
+        CostTable::from_costs(vec![cost as u8; u8::MAX as usize + 1])
+    }
+
+    fn from_costs(costs: Vec<u8>) -> CostTable {
+        let mut table = CostTable { costs, cost_sums: Vec::new() };
+        table.update_cost_sums();
+        table
+    }
+
+    fn update_cost_sums(&mut self) {
+        self.cost_sums = Some(0).into_iter()
+            .chain(self.costs.iter().scan(0, |sum, &cost| { *sum += cost as usize; Some(*sum) }))
+            .collect();
+    }
+
+    pub fn cost(&self, idx: usize) -> usize {
+        self.costs[idx] as usize
+    }
+
+    /// Returns the sum of costs of cards with indices in `begin_idx..end_idx`.
+    pub fn cost_sum(&self, begin_idx: usize, end_idx: usize) -> usize {
+        self.cost_sums[end_idx] - self.cost_sums[begin_idx]
+    }
+
+    pub fn set_cost(&mut self, idx: usize, cost: usize) {
+        self.costs[idx] = cost as u8;
+        self.update_cost_sums();
+    }
+}
+
+impl fmt::Debug for CostTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CostTable").finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Table {
     rows: Vec<Vec<Card>>
 }
@@ -71,7 +124,7 @@ impl Table {
         let mut match_idx = 0;
         for (row_i, row) in self.rows.iter().enumerate() {
             let last = row.last().unwrap();
-            if last.idx() < card.idx() && match_idx < card.idx() {
+            if last.idx() < card.idx() && match_idx < last.idx() {
                 match_row_i = Some(row_i);
                 match_idx = last.idx();
             }
@@ -88,10 +141,118 @@ impl Table {
     }
 }
 
+/// Applies the rules of the game to the table. This is the only place where the cards
+/// played in a round are resolved, so that the simulations and the validation of the
+/// input agree on the rules.
+#[derive(Debug, Clone)]
+pub struct GameEngine<'r> {
+    rules: &'r Rules,
+    table: Table,
+}
+
+/// What happened with the card of a single player in a round.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlayerOutcome {
+    pub card: Card,
+    /// The row where the card was placed.
+    pub row_i: usize,
+    /// The cards that the player had to eat (empty if the card was simply added to the
+    /// row).
+    pub taken: Vec<Card>,
+    /// True if the card was lower than all rows, so the player had to choose the row.
+    pub chose_row: bool,
+}
+
+/// The outcome of a round, indexed by players.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoundOutcome {
+    pub players: Vec<PlayerOutcome>,
+}
+
+impl<'r> GameEngine<'r> {
+    pub fn new(rules: &'r Rules, table: Table) -> GameEngine<'r> {
+        GameEngine { rules, table }
+    }
+
+    pub fn table(&self) -> &Table { &self.table }
+
+    /// Returns true if the card is lower than all rows, so the player who played it must
+    /// choose a row to eat.
+    pub fn needs_row_choice(&self, card: Card) -> bool {
+        self.table.match_row(card).is_none()
+    }
+
+    /// Returns the rows that the player may choose when playing the given card (this is
+    /// empty if the card does not need a row choice).
+    pub fn legal_rows(&self, card: Card) -> Vec<usize> {
+        if self.needs_row_choice(card) {
+            (0..self.rules.row_count).collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Resolves a round where `actions[player_i]` is the card played by player_i. The
+    /// cards are applied in increasing order and `choose_row(table, player_i, card)` is
+    /// called to pick the row whenever a card is lower than all rows.
+    pub fn resolve_round<F>(&mut self, actions: &[Card], mut choose_row: F) -> RoundOutcome
+        where F: FnMut(&Table, usize, Card) -> usize
+    {
+        let mut order = (0..actions.len()).collect::<Vec<_>>();
+        order.sort_unstable_by_key(|&player_i| actions[player_i].idx());
+
+        let mut outcomes = vec![None; actions.len()];
+        for player_i in order {
+            let card = actions[player_i];
+            let outcome =
+                if self.needs_row_choice(card) {
+                    let row_i = choose_row(&self.table, player_i, card);
+                    assert!(row_i < self.rules.row_count);
+                    let taken = self.table.row(row_i).to_vec();
+                    self.table.replace_row(row_i, card);
+                    PlayerOutcome { card, row_i, taken, chose_row: true }
+                } else {
+                    self.play_matching_card(card)
+                };
+            outcomes[player_i] = Some(outcome);
+        }
+
+        RoundOutcome { players: outcomes.into_iter().map(Option::unwrap).collect() }
+    }
+
+    fn play_matching_card(&mut self, card: Card) -> PlayerOutcome {
+        let row_i = self.table.match_row(card).unwrap();
+        let taken =
+            if self.table.row_len(row_i) < self.rules.max_row_len {
+                self.table.push_to_row(row_i, card);
+                Vec::new()
+            } else {
+                let taken = self.table.row(row_i).to_vec();
+                self.table.replace_row(row_i, card);
+                taken
+            };
+        PlayerOutcome { card, row_i, taken, chose_row: false }
+    }
+}
+
+impl PlayerOutcome {
+    pub fn cost(&self) -> usize {
+        self.taken.iter().map(|card| card.cost()).sum()
+    }
+}
+
+impl RoundOutcome {
+    pub fn costs(&self) -> impl Iterator<Item = usize> + '_ {
+        self.players.iter().map(|outcome| outcome.cost())
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Round {
     pub table: Table,
     pub actions: Vec<Card>,
+    /// The rows chosen by players whose cards were lower than all rows (if recorded).
+    pub row_choices: Vec<Option<usize>>,
 }
 
 #[derive(Debug, Clone)]
@@ -101,15 +262,96 @@ pub struct Rules {
     pub max_row_len: usize,
     pub hand_len: usize,
     pub row_count: usize,
+    pub variant: Variant,
+    pub costs: CostTable,
+    /// The match ends when a player reaches this score.
+    pub match_end_score: usize,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Variant {
+    /// The deck contains all cards and the cards that are not dealt stay in the deck.
+    Standard,
+    /// The deck contains only the cards for the rows and hands of all players, so all
+    /// cards are in play (see `Rules::restrict_deck()`).
+    Tactical,
 }
 
 impl Rules {
+    /// The rules of the standard game with 104 cards.
+    pub fn standard() -> Rules {
+        Rules {
+            min_card_idx: 1,
+            max_card_idx: 104,
+            max_row_len: 5,
+            hand_len: 10,
+            row_count: 4,
+            variant: Variant::Standard,
+            costs: CostTable::standard(),
+            match_end_score: 66,
+        }
+    }
+
+    /// Returns true if all cards from the deck are dealt to the players or the rows.
+    pub fn all_cards_dealt(&self) -> bool {
+        self.variant == Variant::Tactical
+    }
+
+    /// In the tactical variant, restricts the deck to the cards that are dealt to the rows
+    /// and to the hands of the given number of players.
+    pub fn restrict_deck(&mut self, player_count: usize) {
+        if self.variant == Variant::Tactical {
+            self.max_card_idx = self.min_card_idx + self.row_count + self.hand_len * player_count - 1;
+        }
+    }
+
+    /// Checks that the rules make sense.
+    pub fn check(&self) -> Result<(), &'static str> {
+        if self.min_card_idx < 1 { return Err("the lowest card must be at least 1"); }
+        if self.max_card_idx > u8::MAX as usize { return Err("the highest card must be at most 255"); }
+        if self.min_card_idx > self.max_card_idx { return Err("the deck is empty"); }
+        if self.max_row_len < 1 { return Err("the rows must have at least one card"); }
+        if self.hand_len < 1 { return Err("the hands must have at least one card"); }
+        if self.row_count < 1 { return Err("there must be at least one row"); }
+        Ok(())
+    }
+
+    /// Checks that there are enough cards in the deck for the given number of players.
+    pub fn check_player_count(&self, player_count: usize) -> Result<(), &'static str> {
+        if self.row_count + self.hand_len * player_count > self.card_count() {
+            return Err("there are not enough cards in the deck for all players");
+        }
+        if self.all_cards_dealt() && self.row_count + self.hand_len * player_count != self.card_count() {
+            return Err("all cards in the deck must be dealt in the tactical variant");
+        }
+        Ok(())
+    }
+
     pub fn card_count(&self) -> usize {
         self.max_card_idx - self.min_card_idx + 1
     }
 
-    pub fn cards(&self) -> impl Iterator<Item = Card> {
-        (self.min_card_idx..=self.max_card_idx).map(Card::new)
+    pub fn card(&self, idx: usize) -> Card {
+        Card::new(idx, self.costs.cost(idx))
+    }
+
+    pub fn cards(&self) -> impl Iterator<Item = Card> + '_ {
+        (self.min_card_idx..=self.max_card_idx).map(move |idx| self.card(idx))
+    }
+
+    /// Returns the mean cost of cards with indices in `begin_idx..end_idx`, excluding the
+    /// `seen_cards` (which may also contain cards outside of the range). Returns zero if
+    /// all cards in the range were seen.
+    pub fn mean_cost<'c, I>(&self, begin_idx: usize, end_idx: usize, seen_cards: I) -> f32
+        where I: IntoIterator<Item = &'c Card>
+    {
+        let mut cost_sum = self.costs.cost_sum(begin_idx, end_idx);
+        let mut count = end_idx - begin_idx;
+        for card in seen_cards.into_iter().filter(|card| (begin_idx..end_idx).contains(&card.idx())) {
+            cost_sum -= card.cost();
+            count -= 1;
+        }
+        if count == 0 { 0. } else { cost_sum as f32 / count as f32 }
     }
 }
 
@@ -119,24 +361,136 @@ pub struct GameState {
     pub past_rounds: Vec<Round>,
     pub table: Table,
     pub player_count: usize,
+    /// The current round, if its actions have already been revealed and I have to choose
+    /// the row that my card will eat.
+    pub pending_round: Option<Round>,
+    /// The scores of the players from the previous deals, if we play a match of several
+    /// deals.
+    pub scores: Option<Vec<usize>>,
+}
+
+impl GameState {
+    /// Iterates over all rounds in which we have observed the actions of the players,
+    /// including the pending round.
+    pub fn observed_rounds(&self) -> impl Iterator<Item = &Round> {
+        self.past_rounds.iter().chain(self.pending_round.iter())
+    }
+
+    /// Returns true if all cards have been played, so the hands of all players are known.
+    pub fn is_finished(&self) -> bool {
+        self.my_hand.is_empty() && self.pending_round.is_none()
+    }
+
+    /// Returns the costs that the players have collected in the past rounds of this deal.
+    /// A row choice that was not recorded is inferred from the table of the next round.
+    pub fn deal_costs(&self, rules: &Rules) -> Vec<usize> {
+        let mut costs = vec![0; self.player_count];
+        for (round_i, round) in self.past_rounds.iter().enumerate() {
+            let next_table = self.past_rounds.get(round_i + 1)
+                .map_or(&self.table, |next_round| &next_round.table);
+            let outcomes = (0..rules.row_count).map(|choice_row_i| {
+                let mut engine = GameEngine::new(rules, round.table.clone());
+                let outcome = engine.resolve_round(&round.actions, |_, player_i, _|
+                    round.row_choices[player_i].unwrap_or(choice_row_i));
+                (outcome, engine.table() == next_table)
+            }).collect::<Vec<_>>();
+            let (outcome, _) = outcomes.iter()
+                .find(|(_, is_next_table)| *is_next_table)
+                .unwrap_or(&outcomes[0]);
+            for (cost, round_cost) in costs.iter_mut().zip(outcome.costs()) {
+                *cost += round_cost;
+            }
+        }
+        costs
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use crate::test_utils::{GameBuilder, make_table, small_rules};
     use super::*;
 
     #[test]
     fn test_card_costs() {
-        assert_eq!(Card::new(2).cost(), 1);
-        assert_eq!(Card::new(5).cost(), 2);
-        assert_eq!(Card::new(15).cost(), 2);
-        assert_eq!(Card::new(25).cost(), 2);
-        assert_eq!(Card::new(10).cost(), 3);
-        assert_eq!(Card::new(20).cost(), 3);
-        assert_eq!(Card::new(30).cost(), 3);
-        assert_eq!(Card::new(11).cost(), 5);
-        assert_eq!(Card::new(22).cost(), 5);
-        assert_eq!(Card::new(33).cost(), 5);
-        assert_eq!(Card::new(55).cost(), 7);
+        let costs = CostTable::standard();
+        assert_eq!(costs.cost(2), 1);
+        assert_eq!(costs.cost(5), 2);
+        assert_eq!(costs.cost(15), 2);
+        assert_eq!(costs.cost(25), 2);
+        assert_eq!(costs.cost(10), 3);
+        assert_eq!(costs.cost(20), 3);
+        assert_eq!(costs.cost(30), 3);
+        assert_eq!(costs.cost(11), 5);
+        assert_eq!(costs.cost(22), 5);
+        assert_eq!(costs.cost(33), 5);
+        assert_eq!(costs.cost(55), 7);
+
+        let costs = CostTable::flat(1);
+        assert_eq!(costs.cost(55), 1);
+    }
+
+    #[test]
+    fn test_mean_cost() {
+        let rules = Rules::standard();
+        assert_eq!(rules.mean_cost(50, 56, &[]), (3. + 1. + 1. + 1. + 1. + 7.) / 6.);
+        assert_eq!(rules.mean_cost(1, 5, &[]), 1.);
+
+        let seen = [rules.card(55), rules.card(80), rules.card(51)];
+        assert_eq!(rules.mean_cost(50, 56, &seen), (3. + 1. + 1. + 1.) / 4.);
+        assert_eq!(rules.mean_cost(55, 56, &seen), 0.);
+    }
+
+    #[test]
+    fn test_match_row() {
+        let rules = Rules::standard();
+        let table = make_table(&rules, &[&[50], &[10], &[70], &[30]]);
+        assert_eq!(table.match_row(rules.card(61)), Some(0));
+        assert_eq!(table.match_row(rules.card(35)), Some(3));
+        assert_eq!(table.match_row(rules.card(104)), Some(2));
+        assert_eq!(table.match_row(rules.card(5)), None);
+    }
+
+    #[test]
+    fn test_resolve_round() {
+        let rules = Rules::standard();
+        let table = make_table(&rules, &[&[2, 4, 10], &[48, 49, 68, 84], &[51, 64, 72], &[9, 22, 32, 47, 50]]);
+        let mut engine = GameEngine::new(&rules, table);
+
+        let actions = [20, 61, 80, 1].iter().map(|&idx| rules.card(idx)).collect::<Vec<_>>();
+        let outcome = engine.resolve_round(&actions, |_, player_i, card| {
+            assert_eq!((player_i, card), (3, rules.card(1)));
+            1
+        });
+
+        assert_eq!(outcome.players[0], PlayerOutcome {
+            card: rules.card(20), row_i: 0, taken: Vec::new(), chose_row: false });
+        assert_eq!(outcome.players[1].row_i, 3);
+        assert_eq!(outcome.players[1].cost(), 1 + 5 + 1 + 1 + 3);
+        assert_eq!(outcome.players[2].row_i, 2);
+        assert_eq!(outcome.players[3].taken.len(), 4);
+        assert!(outcome.players[3].chose_row);
+        assert_eq!(outcome.costs().collect::<Vec<_>>(), vec![0, 11, 0, 4]);
+
+        let table = engine.table();
+        assert_eq!(table.row(0), make_table(&rules, &[&[2, 4, 10, 20]]).row(0));
+        assert_eq!(table.row(1), &[rules.card(1)]);
+        assert_eq!(table.row(3), &[rules.card(61)]);
+    }
+
+    #[test]
+    fn test_deal_costs() {
+        // the rows cost 3 and 4, bob eats the second row with his 5 and then I play 12 on
+        // his 5, which tells us which row he has chosen
+        let rules = small_rules(Variant::Standard, 50, 5, 3, 2);
+        let game = GameBuilder::new(&rules, 2, &[&[10], &[20, 21]]);
+        assert_eq!(game.state(&[30, 12, 45]).deal_costs(&rules), vec![0, 0]);
+
+        let mut state = game
+            .round_with_choices(&[30, 5], &[None, Some(1)])
+            .round(&[12, 40])
+            .state(&[45]);
+        assert_eq!(state.deal_costs(&rules), vec![0, 4]);
+        state.past_rounds[0].row_choices = vec![None, None];
+        assert_eq!(state.deal_costs(&rules), vec![0, 4]);
     }
 }
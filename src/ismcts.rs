@@ -0,0 +1,505 @@
+use std::collections::{HashMap};
+use std::time::{Instant};
+use rand::{Rng, RngCore};
+
+use crate::{endgame, stats, utils};
+use crate::distrib::{HandsDistrib};
+use crate::game::{Card, GameEngine, GameState, Table};
+use crate::mc::{PlayoutConfig};
+use crate::stats::{Estimates, WeightedStats};
+use crate::utils::{SearchBudget};
+
+/// The exploration constant of PUCT, applied to the values normalized to [0, 1] in every
+/// node.
+const EXPLORATION: f32 = 1.;
+
+/// A node in the search tree, which corresponds to my information set: the sequence of
+/// my actions and of the rounds that I observed since the root. The hands of other
+/// players are not part of the tree, they are sampled in every iteration. In the row
+/// choice mode, the actions in the root are the rows that my card can eat and the actions
+/// in the deeper nodes are my cards as usual.
+#[derive(Debug)]
+struct Node {
+    /// My actions indexed by the position of the card in `GameState::my_hand` (or by the
+    /// row in the root in the row choice mode), which point into the vector of actions.
+    actions: Vec<Option<usize>>,
+    visit_count: usize,
+    weight_sum: f64,
+    /// The sum of `weight * value` of all iterations that passed through this node.
+    value_sum: f64,
+}
+
+/// My action in a node of the tree.
+#[derive(Debug)]
+struct Action {
+    visit_count: usize,
+    value_sum: f64,
+    weight_sum: f64,
+    /// The nodes that follow the round in which I played this action, keyed by the cards
+    /// that all players played in the round and by the resulting table.
+    next: HashMap<(Vec<Card>, Table), usize>,
+}
+
+impl Node {
+    fn new(action_count: usize) -> Node {
+        Node { actions: vec![None; action_count], visit_count: 0, weight_sum: 0., value_sum: 0. }
+    }
+}
+
+/// The root of a search tree built by one thread.
+struct RootResult {
+    /// The values of the actions in the root (see `action_value()`) and their weights.
+    values: Vec<f32>,
+    weights: Vec<f64>,
+    /// The statistics of the values of all iterations that started with the action.
+    action_stats: Vec<WeightedStats>,
+    stats: WeightedStats,
+}
+
+/// Estimates the costs of playing each card from my hand (or of eating each row in the row
+/// choice mode) using information-set Monte Carlo tree search. Every iteration draws a
+/// determinization of the hands of other players from `distrib` and descends the tree of
+/// my information sets in all remaining rounds, while other players follow the policy of
+/// the `config`. My actions in the tree are selected by PUCT with the policy of the
+/// `config` as the prior, so that I react to the cards that other players have played,
+/// and the rest of the deal is played out by the policy. The costs are reduced by
+/// `config.eval_costs()`.
+///
+/// The returned cost of an action is the value of the line in which I always play the
+/// most visited action, so it does not include the exploratory actions in the deeper
+/// nodes. The standard errors are estimated from the values of all iterations that
+/// started with the action. As in the flat search, the rest of the deal is evaluated by
+/// the `endgame` solver once the hands have at most `config.endgame_len` cards.
+///
+/// Every thread searches its own tree with a part of the `budget` and the roots of the
+/// trees are merged. Every action in the root is visited at least once in every tree, even
+/// if the `budget` is smaller.
+pub fn estimate_rel_costs(
+    rng: &mut dyn RngCore, config: &PlayoutConfig,
+    state: &GameState, distrib: &HandsDistrib, thread_count: usize, budget: SearchBudget,
+) -> Estimates
+{
+    let root_results = utils::run_parallel(rng, thread_count, budget.iterations,
+        |rng, thread_iters| {
+            let thread_budget = SearchBudget { iterations: thread_iters.len(), ..budget };
+            search_tree(rng, config, state, distrib, thread_budget)
+        });
+
+    let action_count = root_results[0].values.len();
+    let mut value_sums = vec![0.; action_count];
+    let mut weight_sums = vec![0.; action_count];
+    let mut action_stats = vec![WeightedStats::new(1); action_count];
+    let mut root_stats = WeightedStats::new(1);
+    for result in root_results.iter() {
+        for action_i in 0..action_count {
+            value_sums[action_i] += result.weights[action_i] * result.values[action_i] as f64;
+            weight_sums[action_i] += result.weights[action_i];
+            action_stats[action_i].merge(&result.action_stats[action_i]);
+        }
+        root_stats.merge(&result.stats);
+    }
+
+    // the values of different actions come from different iterations, so they are
+    // independent and the standard error of their difference follows from their own
+    let means = value_sums.iter().zip(weight_sums.iter())
+        .map(|(value_sum, weight_sum)| (value_sum / weight_sum) as f32)
+        .collect::<Vec<_>>();
+    let std_errors = action_stats.iter().map(|stats| stats.std_error(0)).collect::<Vec<_>>();
+    let best_i = stats::best_i(&means);
+    let diff_std_errors = (0..means.len())
+        .map(|i| if i == best_i { 0. } else { std_errors[i].hypot(std_errors[best_i]) })
+        .collect();
+    Estimates {
+        means,
+        std_errors,
+        diff_std_errors,
+        sample_count: root_stats.sample_count(),
+        effective_sample_size: root_stats.effective_sample_size(),
+    }
+}
+
+/// Builds a single search tree (see `estimate_rel_costs()`).
+fn search_tree(
+    rng: &mut dyn RngCore, config: &PlayoutConfig,
+    state: &GameState, distrib: &HandsDistrib, budget: SearchBudget,
+) -> RootResult
+{
+    let rules = config.rules;
+    let my_hand = &state.my_hand;
+    let root_action_count = match state.pending_round {
+        Some(_) => rules.row_count,
+        None => my_hand.len(),
+    };
+    let mut nodes = vec![Node::new(root_action_count)];
+    let mut actions = Vec::<Action>::new();
+    let mut action_stats = vec![WeightedStats::new(1); root_action_count];
+    let mut stats = WeightedStats::new(1);
+
+    let start_time = Instant::now();
+    let mut iter_i = 0;
+    loop {
+        let root_expanded = nodes[0].actions.iter().all(|action_i| action_i.is_some());
+        if root_expanded && budget.is_exhausted(start_time, iter_i) {
+            break
+        }
+        iter_i += 1;
+
+        let (mut hands, weight) = distrib.sample(rng, rules);
+        hands[0] = my_hand.clone();
+        let player_count = hands.len();
+
+        // the nodes and the actions that this iteration visited in the tree; `node_i` is
+        // the current node, or `None` when the iteration has left the tree
+        let mut path_nodes = vec![0];
+        let mut path_actions = Vec::new();
+        let mut node_i = Some(0);
+        let mut costs = config.deal_costs.clone();
+        let mut engine = match state.pending_round {
+            Some(ref round) => {
+                let priors = vec![1. / root_action_count as f32; root_action_count];
+                let my_row_i = select_action(&nodes[0], &actions, &priors, true);
+                let action_i = add_action(&mut nodes, &mut actions, 0, my_row_i);
+                path_actions.push(action_i);
+
+                let mut engine = GameEngine::new(rules, round.table.clone());
+                let outcome = engine.resolve_round(&round.actions, |table, player_i, card| {
+                    if player_i == 0 { my_row_i }
+                    else {
+                        config.sample_row(rng.gen(), table, player_count,
+                            player_i, card, &hands[player_i])
+                    }
+                });
+                for (cost, round_cost) in costs.iter_mut().zip(outcome.costs()) {
+                    *cost += round_cost as f32;
+                }
+                node_i = next_node(&mut nodes, &mut actions, action_i,
+                    &round.actions, engine.table(), my_hand.len());
+                path_nodes.push(node_i.unwrap_or(nodes.len() - 1));
+                engine
+            },
+            None => GameEngine::new(rules, state.table.clone()),
+        };
+
+        // `my_played` marks the cards from my hand that were already played in this
+        // iteration, so that the actions of a node are indexed in the same way as in the
+        // root
+        let mut my_played = vec![false; my_hand.len()];
+        let mut value = None;
+        for _ in 0..my_hand.len() {
+            // the root must play one of its actions, so that we have something to report
+            if node_i != Some(0) && endgame::is_tractable(&hands, config.endgame_len) {
+                value = Some(endgame::value(rules, config.policy, engine.table(), &hands,
+                    &costs, config.eval_costs));
+                break
+            }
+
+            let hand = &hands[0];
+            let my_card_i = match node_i {
+                Some(node_i) => {
+                    let priors =
+                        if node_i == 0 { vec![1. / hand.len() as f32; my_hand.len()] }
+                        else {
+                            let probs = config.policy.action_probs(rules, engine.table(),
+                                player_count, 0, hand);
+                            let mut priors = vec![0.; my_hand.len()];
+                            for (&card, &prob) in hand.iter().zip(probs.iter()) {
+                                priors[my_hand.iter().position(|&c| c == card).unwrap()] = prob;
+                            }
+                            priors
+                        };
+                    let available_priors = priors.iter().zip(my_played.iter())
+                        .map(|(&prior, &played)| if played { 0. } else { prior })
+                        .collect::<Vec<_>>();
+                    let card_i = select_action(&nodes[node_i], &actions, &available_priors,
+                        node_i == 0);
+                    path_actions.push(add_action(&mut nodes, &mut actions, node_i, card_i));
+                    card_i
+                },
+                None => {
+                    let action_i = config.sample_action(rng.gen(), engine.table(),
+                        player_count, 0, hand);
+                    my_hand.iter().position(|&card| card == hand[action_i]).unwrap()
+                },
+            };
+            my_played[my_card_i] = true;
+
+            let round_actions = (0..player_count).map(|player_i| {
+                let hand = &mut hands[player_i];
+                let action_i =
+                    if player_i == 0 {
+                        hand.iter().position(|&card| card == my_hand[my_card_i]).unwrap()
+                    } else {
+                        config.sample_action(rng.gen(), engine.table(),
+                            player_count, player_i, hand)
+                    };
+                hand.swap_remove(action_i)
+            }).collect::<Vec<Card>>();
+
+            let outcome = engine.resolve_round(&round_actions, |table, player_i, card|
+                config.sample_row(rng.gen(), table, player_count,
+                    player_i, card, &hands[player_i]));
+            for (cost, round_cost) in costs.iter_mut().zip(outcome.costs()) {
+                *cost += round_cost as f32;
+            }
+
+            if node_i.is_some() {
+                let action_i = *path_actions.last().unwrap();
+                node_i = next_node(&mut nodes, &mut actions, action_i,
+                    &round_actions, engine.table(), my_hand.len());
+                path_nodes.push(node_i.unwrap_or(nodes.len() - 1));
+            }
+        }
+
+        let value = value.unwrap_or_else(|| (config.eval_costs)(&costs));
+        for &node_i in path_nodes.iter() {
+            let node = &mut nodes[node_i];
+            node.visit_count += 1;
+            node.weight_sum += weight as f64;
+            node.value_sum += (weight * value) as f64;
+        }
+        for &action_i in path_actions.iter() {
+            let action = &mut actions[action_i];
+            action.visit_count += 1;
+            action.weight_sum += weight as f64;
+            action.value_sum += (weight * value) as f64;
+        }
+        let root_action_i = nodes[0].actions.iter()
+            .position(|&action_i| action_i == Some(path_actions[0])).unwrap();
+        action_stats[root_action_i].add(weight, &[value]);
+        stats.add(weight, &[value]);
+    }
+
+    let root_actions = nodes[0].actions.iter().map(|action_i| action_i.unwrap());
+    RootResult {
+        values: root_actions.clone().map(|action_i| action_value(&nodes, &actions, action_i))
+            .collect(),
+        weights: root_actions.map(|action_i| actions[action_i].weight_sum).collect(),
+        action_stats,
+        stats,
+    }
+}
+
+/// Returns the index of my action `action_i` in the node, adding it to the tree if it has
+/// not been played in this node yet.
+fn add_action(nodes: &mut [Node], actions: &mut Vec<Action>, node_i: usize, action_i: usize)
+    -> usize
+{
+    *nodes[node_i].actions[action_i].get_or_insert_with(|| {
+        actions.push(Action {
+            visit_count: 0, value_sum: 0., weight_sum: 0., next: HashMap::new(),
+        });
+        actions.len() - 1
+    })
+}
+
+/// Moves to the node that follows my action `action_i` in a round with the given
+/// `round_actions` that led to the `table`. If the node does not exist yet, it is added to
+/// the tree (with actions for the `hand_len` cards of my hand) and we return `None`,
+/// because the rest of the iteration is then a playout outside of the tree.
+fn next_node(
+    nodes: &mut Vec<Node>, actions: &mut [Action], action_i: usize,
+    round_actions: &[Card], table: &Table, hand_len: usize,
+) -> Option<usize>
+{
+    let key = (round_actions.to_vec(), table.clone());
+    if let Some(&node_i) = actions[action_i].next.get(&key) {
+        return Some(node_i)
+    }
+    nodes.push(Node::new(hand_len));
+    actions[action_i].next.insert(key, nodes.len() - 1);
+    None
+}
+
+/// Selects my action in the node using PUCT. The values are costs, so we prefer low
+/// values, and they are normalized to [0, 1] using the range of the values of the actions
+/// in this node, so that the exploration does not depend on the scale of the costs. The
+/// exploration term of an action is proportional to its prior probability, and actions
+/// that were never played from this node have the worst value. Actions with zero prior are
+/// never selected. In the root, every action is tried once first.
+fn select_action(node: &Node, actions: &[Action], priors: &[f32], is_root: bool) -> usize {
+    let action_values = node.actions.iter()
+        .map(|action_i| action_i.map(|action_i| {
+            let action = &actions[action_i];
+            (action.value_sum / action.weight_sum) as f32
+        }))
+        .collect::<Vec<_>>();
+    let visited_values = action_values.iter().zip(priors.iter())
+        .filter(|&(_, &prior)| prior > 0.)
+        .filter_map(|(&value, _)| value);
+    let min_value = visited_values.clone().fold(f32::INFINITY, f32::min);
+    let max_value = visited_values.fold(-f32::INFINITY, f32::max);
+    let value_range = if max_value > min_value { max_value - min_value } else { 1. };
+    let sqrt_visit_count = (node.visit_count.max(1) as f32).sqrt();
+
+    let mut best_action_i = None;
+    let mut best_score = -f32::INFINITY;
+    for (action_i, &prior) in priors.iter().enumerate() {
+        if prior <= 0. { continue }
+        let (norm_value, visit_count) = match node.actions[action_i] {
+            Some(child_i) => {
+                let value = action_values[action_i].unwrap();
+                ((max_value - value) / value_range, actions[child_i].visit_count)
+            },
+            None if is_root => return action_i,
+            None => (0., 0),
+        };
+        let score = norm_value
+            + EXPLORATION * prior * sqrt_visit_count / (1 + visit_count) as f32;
+        if score > best_score {
+            best_action_i = Some(action_i);
+            best_score = score;
+        }
+    }
+    best_action_i.unwrap()
+}
+
+/// Returns the value of the node when I play its most visited action (the robust child),
+/// or the mean value of the iterations if no action was played from the node.
+fn node_value(nodes: &[Node], actions: &[Action], node_i: usize) -> f32 {
+    let node = &nodes[node_i];
+    let best_action_i = node.actions.iter()
+        .filter_map(|&action_i| action_i)
+        .max_by_key(|&action_i| actions[action_i].visit_count);
+    match best_action_i {
+        Some(action_i) => action_value(nodes, actions, action_i),
+        None => (node.value_sum / node.weight_sum) as f32,
+    }
+}
+
+/// Returns the value of the action, which is the weighted mean of the values of the
+/// nodes that follow it (every iteration that played the action continues to one of
+/// them).
+fn action_value(nodes: &[Node], actions: &[Action], action_i: usize) -> f32 {
+    let (value_sum, weight_sum) = actions[action_i].next.values()
+        .map(|&node_i| (node_value(nodes, actions, node_i), nodes[node_i].weight_sum))
+        .fold((0., 0.), |(value_sum, weight_sum), (value, weight)| {
+            (value_sum + value as f64 * weight, weight_sum + weight)
+        });
+    (value_sum / weight_sum) as f32
+}
+
+#[cfg(test)]
+mod test {
+    use rand_pcg::{Pcg64Mcg};
+    use crate::{endgame, mc};
+    use crate::distrib::{Inference};
+    use crate::game::{Round, Variant};
+    use crate::objective::{Objective};
+    use crate::policy::{Policy1, RandomPolicy};
+    use crate::test_utils::{GameBuilder, make_cards, small_rules};
+    use super::*;
+
+    #[test]
+    fn test_later_actions() {
+        // all cards are known: I have 6 8 5 and bob has 3 4 1 and plays at random. My 8 is
+        // the best first card when I play well in the later rounds, but the flat search
+        // plays my later cards by the rollout policy and prefers my 6
+        let rules = small_rules(Variant::Tactical, 8, 3, 3, 2);
+        let state = GameBuilder::new(&rules, 2, &[&[2], &[7]]).state(&[6, 8, 5]);
+        let hands = vec![state.my_hand.clone(), make_cards(&rules, &[3, 4, 1])];
+        let my_cost = |costs: &[f32]| costs[0];
+        let config = PlayoutConfig {
+            rules: &rules,
+            policy: &RandomPolicy,
+            endgame_len: 0,
+            eval_costs: &my_cost,
+            deal_costs: vec![0., 0.],
+        };
+
+        let exact_values = endgame::action_values(&rules, &RandomPolicy, &state.table, &hands,
+            &[0., 0.], &my_cost);
+        assert_eq!(stats::best_i(&exact_values), 1);
+
+        let mut rng = Pcg64Mcg::new(42);
+        let mut flat_stats = WeightedStats::new(3);
+        for _ in 0..4000 {
+            flat_stats.add(1., &mc::estimate_policy_2_rel_costs(&mut rng, &config,
+                &state.table, &hands, 3));
+        }
+        assert_eq!(flat_stats.estimates().best_i(), 0);
+
+        let distrib = HandsDistrib::exact(&rules, &RandomPolicy, &state);
+        let budget = SearchBudget { iterations: 20000, time: None };
+        let estimates = estimate_rel_costs(&mut rng, &config, &state, &distrib, 1, budget);
+        assert_eq!(estimates.best_i(), 1);
+        assert!((estimates.means[1] - exact_values[1]).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_realistic_deal() {
+        // three players after two rounds: my later cards react to the cards of other
+        // players, so the search must find a line at least as good as the flat search
+        let rules = small_rules(Variant::Tactical, 34, 5, 6, 4);
+        let state = GameBuilder::new(&rules, 3, &[&[3], &[11], &[20], &[27]])
+            .round(&[12, 21, 5])
+            .round(&[28, 13, 22])
+            .state(&[8, 17, 25, 33]);
+        let policy = Policy1::default();
+        let rel_cost = |costs: &[f32]| Objective::RelCost.eval_costs(&[0; 3], 66, costs);
+        let config = PlayoutConfig {
+            rules: &rules,
+            policy: &policy,
+            endgame_len: 2,
+            eval_costs: &rel_cost,
+            deal_costs: state.deal_costs(&rules).into_iter().map(|cost| cost as f32).collect(),
+        };
+        let mut rng = Pcg64Mcg::new(42);
+        let distrib_budget = SearchBudget { iterations: 5000, time: None };
+        let distrib = HandsDistrib::estimate(&mut rng, &rules, &policy, &state,
+            Inference::Mcmc, 1, distrib_budget);
+
+        let mut flat_stats = WeightedStats::new(state.my_hand.len());
+        for _ in 0..4000 {
+            let (mut hands, weight) = distrib.sample(&mut rng, &rules);
+            hands[0] = state.my_hand.clone();
+            flat_stats.add(weight, &mc::estimate_policy_2_rel_costs(&mut rng, &config,
+                &state.table, &hands, state.my_hand.len()));
+        }
+        let flat_estimates = flat_stats.estimates();
+        let flat_best_i = flat_estimates.best_i();
+
+        let budget = SearchBudget { iterations: 10000, time: None };
+        let estimates = estimate_rel_costs(&mut rng, &config, &state, &distrib, 1, budget);
+        let best_i = estimates.best_i();
+        let std_error = estimates.std_errors[best_i].hypot(flat_estimates.std_errors[flat_best_i]);
+        assert!(estimates.means[best_i] <= flat_estimates.means[flat_best_i] + 2. * std_error);
+    }
+
+    #[test]
+    fn test_row_choice() {
+        // my 1 and bob's 3 are lower than both rows, so I choose between the row 5 (cost 2)
+        // and the row 7 (cost 1), and then bob puts his 3 on my 1
+        let rules = small_rules(Variant::Tactical, 8, 3, 3, 2);
+        let mut state = GameBuilder::new(&rules, 2, &[&[5], &[7]]).state(&[2, 8]);
+        state.pending_round = Some(Round {
+            table: state.table.clone(),
+            actions: make_cards(&rules, &[1, 3]),
+            row_choices: vec![None, None],
+        });
+        let my_cost = |costs: &[f32]| costs[0];
+        let config = PlayoutConfig {
+            rules: &rules,
+            policy: &RandomPolicy,
+            endgame_len: 0,
+            eval_costs: &my_cost,
+            deal_costs: vec![0., 0.],
+        };
+        let distrib = HandsDistrib::exact(&rules, &RandomPolicy, &state);
+        let mut rng = Pcg64Mcg::new(42);
+
+        // every row is evaluated even with a tiny budget
+        let budget = SearchBudget { iterations: 1, time: None };
+        let estimates = estimate_rel_costs(&mut rng, &config, &state, &distrib, 1, budget);
+        assert_eq!(estimates.sample_count, 2);
+        assert!(estimates.means.iter().all(|mean| mean.is_finite()));
+
+        let hands = vec![state.my_hand.clone(), make_cards(&rules, &[4, 6])];
+        let round = state.pending_round.as_ref().unwrap();
+        let config = PlayoutConfig { endgame_len: 2, ..config };
+        let exact_values = mc::estimate_row_choice_rel_costs(&mut rng, &config,
+            &round.table, &round.actions, &hands, 2);
+        let budget = SearchBudget { iterations: 10000, time: None };
+        let estimates = estimate_rel_costs(&mut rng, &config, &state, &distrib, 1, budget);
+        assert_eq!(estimates.best_i(), stats::best_i(&exact_values));
+    }
+}
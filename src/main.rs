@@ -1,70 +1,484 @@
-use std::{collections::{HashSet}, io, env, fs};
+use std::{collections::{HashSet}, ffi::{OsString}, io, env, fs, time::{Duration, Instant}};
+use std::path::{Path, PathBuf};
+use rand::{RngCore};
 use rand_pcg::{Pcg64Mcg};
 
-use crate::distrib::{HandsDistrib};
-use crate::game::{Card, GameState, Round, Rules, Table};
+use crate::distrib::{HandsDistrib, Inference, Sampling};
+use crate::mc::{PlayoutConfig};
+use crate::objective::{Objective};
+use crate::policy::{CostTransform, ModeledPolicy, PlayerModel, Policy, PolicyTransform};
+use crate::profile::{Profile};
+use crate::smc::{ParticleFilter};
+use crate::stats::{Estimates, WeightedStats};
+use crate::utils::{SearchBudget};
+use crate::game::{Card, CostTable, GameEngine, GameState, Round, Rules, Table, Variant};
 
 mod card_matrix;
 mod distrib;
+mod endgame;
+mod fit;
 mod game;
+mod ismcts;
 mod mc;
+mod mcmc;
+mod objective;
 mod policy;
+mod profile;
+mod smc;
+mod stats;
+#[cfg(test)]
+mod test_utils;
 mod utils;
 
+const USAGE: &str = "Use: deep_moo [--objective <name>] [--search flat|ismcts] \
+    [--inference importance|mcmc|smc|exact] [--particles <file>] \
+    [--sampling joint|resampled|marginal] \
+    [--inference-policy <name>] [--rollout-policy <name>] [--no-fit-models] \
+    [--profiles <dir>] \
+    [--iterations <count>] [--time <seconds>] [--margin <sigmas>] [--endgame <cards>] \
+    [--threads <count>] [--seed <number>] <game.txt>";
+
+/// The default number of threads. It does not depend on the number of cores, so that the
+/// program prints the same output for the same seed on every machine.
+const DEFAULT_THREAD_COUNT: usize = 4;
+
+/// The number of standard errors by which an action must be worse than the best action
+/// to be considered distinguishable from it in the output.
+const SAME_AS_BEST_MARGIN: f32 = 2.;
+
+/// The minimal number of samples before the flat search can stop early, because we do not
+/// trust the standard errors estimated from fewer samples.
+const MIN_SAMPLE_COUNT: usize = 2000;
+
+struct Options {
+    input_path: OsString,
+    objective: Option<Objective>,
+    search: Search,
+    inference: Inference,
+    particles_path: Option<OsString>,
+    sampling: Sampling,
+    /// The name of the policy of other players assumed when estimating their hands.
+    inference_policy: String,
+    /// The name of the policy of all players (including my later actions) in the
+    /// playouts.
+    rollout_policy: String,
+    /// Adjust both policies to the cards that every other player has played (see `fit`),
+    /// otherwise only to the profiles.
+    fit_models: bool,
+    /// The directory with the profiles of players from previous games (see `profile`).
+    profiles_dir: Option<OsString>,
+    iterations: Option<usize>,
+    time: Option<Duration>,
+    margin: f32,
+    endgame_len: usize,
+    thread_count: usize,
+    seed: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Search {
+    /// Varies only my first action and plays the rest with the rollout policy.
+    Flat,
+    /// Searches over my actions in all remaining rounds (see `ismcts`).
+    Ismcts,
+}
+
 fn main() -> io::Result<()> {
-    let rules = Rules {
-        min_card_idx: 1,
-        max_card_idx: 104,
-        max_row_len: 5,
-        hand_len: 10,
-        row_count: 4,
+    let options = parse_args()?;
+    let (rules, transform, state, player_names) = {
+        let input_file = fs::File::open(&options.input_path)?;
+        let mut input = io::BufReader::new(input_file);
+        read_game_state(&mut input)?
     };
+    // the names were checked in `parse_args()`
+    let inference_policy = policy::from_name(&options.inference_policy, transform).unwrap();
+    let rollout_policy = policy::from_name(&options.rollout_policy, transform).unwrap();
 
-    let state = {
-        let args = env::args_os().collect::<Vec<_>>();
-        if args.len() != 2 {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Use: deep_moo <game.txt>"));
+    // the profile of player 0 (me) is always empty
+    let mut profiles = vec![Profile::default(); state.player_count];
+    if let Some(ref dir) = options.profiles_dir {
+        for player_i in 1..state.player_count {
+            profiles[player_i] = load_profile(dir, &player_names[player_i])?;
+        }
+    }
+    if state.is_finished() {
+        return match options.profiles_dir {
+            Some(ref dir) => record_game(&rules, &*inference_policy, &state,
+                &player_names, &mut profiles, dir),
+            None => {
+                let msg = "the game has already finished (use --profiles to record it)";
+                Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            },
         }
+    }
+    for (profile, name) in profiles.iter_mut().zip(player_names.iter()) {
+        if !profile.is_compatible(&*inference_policy) {
+            eprintln!("warning: ignoring the profile of {}, which was recorded with a different \
+                policy ({})", name, profile.policy().unwrap());
+            *profile = Profile::default();
+        }
+    }
+    if options.inference == Inference::Exact && !distrib::is_exact_tractable(&rules, &state) {
+        let msg = "the hands of other players can be dealt in too many ways for --inference exact";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
 
-        let input_file = fs::File::open(&args[1])?;
-        let mut input = io::BufReader::new(input_file);
-        read_game_state(&mut input, &rules)?
+    // the estimation of hands may use a quarter of the time, the rest is left for the
+    // search
+    let start_time = Instant::now();
+    let mut rng = Pcg64Mcg::new(options.seed as u128);
+    let distrib_budget = SearchBudget {
+        iterations: 10000,
+        time: options.time.map(|time| time / 4),
+    };
+    let mut distrib = match options.particles_path {
+        Some(ref path) => estimate_saved_particles(&mut rng, &rules, &*inference_policy,
+            &state, path, options.thread_count, distrib_budget)?,
+        None => HandsDistrib::estimate(&mut rng, &rules, &*inference_policy, &state,
+            options.inference, options.thread_count, distrib_budget),
     };
 
-    let mut rng = Pcg64Mcg::new(0xcafef00dd15ea5e5);
-    let distrib = HandsDistrib::estimate(&mut rng, &rules, &state);
+    // the models are fitted to the estimate under the base policy, which we then reweight
+    // to the adjusted policy instead of estimating the hands again
+    let mut rollout_policy = rollout_policy;
+    let models =
+        if options.fit_models {
+            Some(fit::fit_models(&mut rng, &rules, &*inference_policy, &state, &distrib,
+                &profiles, 1000))
+        } else if profiles.iter().any(|profile| profile.decision_count() > 0) {
+            Some(fit::fit_profile_models(&profiles))
+        } else {
+            None
+        };
+    if let Some(models) = models {
+        for (player_i, model) in models.iter().enumerate().skip(1) {
+            println!("# player {}: temperature {:.2}, card bias {:.1}",
+                player_names[player_i], model.temperature, model.card_bias);
+        }
+        if models.iter().any(|&model| model != PlayerModel::NEUTRAL) {
+            let inference_policy = ModeledPolicy::new(inference_policy, models.clone());
+            distrib = distrib.reweight(&rules, inference_policy.base(), &inference_policy, &state);
+            rollout_policy = Box::new(ModeledPolicy::new(rollout_policy, models));
+        }
+    }
+    distrib.set_sampling(&mut rng, options.sampling);
+
+    let diagnostics = distrib.diagnose(&mut rng, &rules, 1000);
+    if diagnostics.is_degenerate() {
+        eprintln!("warning: the estimate of the hands of other players is unreliable ({})",
+            diagnostics);
+    }
+
+    // In the row choice mode we rank the rows that my card can eat, otherwise we rank
+    // the cards in my hand.
+    let labels = match state.pending_round {
+        Some(_) => (1..=rules.row_count).collect::<Vec<_>>(),
+        None => state.my_hand.iter().map(|card| card.idx()).collect::<Vec<_>>(),
+    };
 
-    let mut weight_sum = 0.;
-    let mut rel_costs_sum = vec![0.; state.my_hand.len()];
-    for _ in 0..10000 {
-        let (mut hands, weight) = distrib.sample(&mut rng, &rules);
-        hands[0] = state.my_hand.clone();
+    // In the match mode we maximize the probability of winning the match by default,
+    // otherwise we minimize my cost relative to other players.
+    let objective = options.objective.unwrap_or(
+        if state.scores.is_some() { Objective::WinMatch } else { Objective::RelCost });
+    let scores = state.scores.clone().unwrap_or_else(|| vec![0; state.player_count]);
+    let eval_costs = |costs: &[f32]| objective.eval_costs(&scores, rules.match_end_score, costs);
 
-        let rel_costs = mc::estimate_policy_2_rel_costs(
-            &mut rng, &rules, &state.table, &hands, state.my_hand.len());
+    let search = options.search;
+    let budget = SearchBudget {
+        iterations: options.iterations.unwrap_or(match search {
+            Search::Flat => 10000,
+            Search::Ismcts => 100000,
+        }),
+        time: options.time.map(|time| time.saturating_sub(start_time.elapsed())),
+    };
+    let config = PlayoutConfig {
+        rules: &rules,
+        policy: &*rollout_policy,
+        endgame_len: options.endgame_len,
+        eval_costs: &eval_costs,
+        deal_costs: state.deal_costs(&rules).into_iter().map(|cost| cost as f32).collect(),
+    };
+    let estimates = match search {
+        Search::Flat => estimate_flat_rel_costs(&mut rng, &config, &state, &distrib,
+            options.thread_count, budget, options.margin),
+        Search::Ismcts => ismcts::estimate_rel_costs(&mut rng, &config, &state, &distrib,
+            options.thread_count, budget),
+    };
+    let rel_costs = &estimates.means;
 
-        weight_sum += weight;
-        for i in 0..state.my_hand.len() {
-            rel_costs_sum[i] += rel_costs[i] * weight;
+    let mut best_is = (0..labels.len()).collect::<Vec<_>>();
+    best_is.sort_by(|&i, &j| utils::compare_f32(rel_costs[i], rel_costs[j]));
+
+    println!("# objective: {}", objective.name());
+    println!("# samples: {} (effective {:.0})",
+        estimates.sample_count, estimates.effective_sample_size);
+    for &i in best_is.iter() {
+        // mark the actions that cannot be distinguished from the best action
+        let flag = if estimates.is_worse_than_best(i, SAME_AS_BEST_MARGIN) { "" } else { " *" };
+        println!("{:3} {:6.2} {:5.2}{}", labels[i], -rel_costs[i], estimates.std_errors[i], flag);
+    }
+
+    Ok(())
+}
+
+/// Estimates the costs of the actions (cards in my hand or rows to eat in the row choice
+/// mode) as the weighted mean of `mc::estimate_policy_2_rel_costs()` over hands sampled
+/// from `distrib`. The samples are split among `thread_count` threads and drawn in
+/// batches until the `budget` is exhausted or the best action is better than all
+/// other actions by `margin` standard errors.
+fn estimate_flat_rel_costs(
+    rng: &mut dyn RngCore, config: &PlayoutConfig,
+    state: &GameState, distrib: &HandsDistrib,
+    thread_count: usize, budget: SearchBudget, margin: f32,
+) -> Estimates
+{
+    let rules = config.rules;
+    let action_count = match state.pending_round {
+        Some(_) => rules.row_count,
+        None => state.my_hand.len(),
+    };
+
+    let mut stats = WeightedStats::new(action_count);
+    utils::run_batches(rng, thread_count, budget, |rng, iter_count| {
+        let mut thread_stats = WeightedStats::new(action_count);
+        for _ in 0..iter_count {
+            let (mut hands, weight) = distrib.sample(rng, rules);
+            hands[0] = state.my_hand.clone();
+
+            let rel_costs = match state.pending_round {
+                Some(ref round) => mc::estimate_row_choice_rel_costs(
+                    rng, config, &round.table, &round.actions, &hands, state.my_hand.len()),
+                None => mc::estimate_policy_2_rel_costs(
+                    rng, config, &state.table, &hands, state.my_hand.len()),
+            };
+            thread_stats.add(weight, &rel_costs);
         }
+        thread_stats
+    }, |thread_stats| {
+        thread_stats.iter().for_each(|thread_stats| stats.merge(thread_stats));
+        stats.sample_count() >= MIN_SAMPLE_COUNT && stats.estimates().is_best_separated(margin)
+    });
+    stats.estimates()
+}
+
+/// Estimates the hands with the particle filter saved in the file at `path` (or with a new
+/// filter if the file does not exist yet), which is updated with the new rounds and saved
+/// back to the file.
+fn estimate_saved_particles(
+    rng: &mut dyn RngCore, rules: &Rules, policy: &dyn Policy,
+    state: &GameState, path: &OsString,
+    thread_count: usize, budget: SearchBudget,
+) -> io::Result<HandsDistrib>
+{
+    let mut filter = match fs::File::open(path) {
+        Ok(file) => ParticleFilter::read(io::BufReader::new(file), rules, state)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound =>
+            ParticleFilter::new(rng, rules, state, budget),
+        Err(err) => return Err(err),
+    };
+    if !filter.is_compatible(policy) {
+        let msg = format!("the particles were conditioned on the game under a different \
+            policy ({})", filter.policy().unwrap());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
     }
+    filter.update(rng, rules, policy, state, thread_count);
+    write_atomically(Path::new(path), |output| filter.write(output))?;
+    Ok(filter.to_distrib(rules, state))
+}
 
-    let rel_costs = rel_costs_sum.iter().map(|x| x / weight_sum).collect::<Vec<_>>();
-    let mut best_action_is = (0..state.my_hand.len()).collect::<Vec<_>>();
-    best_action_is.sort_by(|&i, &j| utils::compare_f32(rel_costs[i], rel_costs[j]));
+/// Loads the profile of the player with the given name from the directory `dir` (the
+/// profile is empty if the player has not been recorded yet).
+fn load_profile(dir: &OsString, name: &str) -> io::Result<Profile> {
+    match fs::File::open(profile_path(dir, name)?) {
+        Ok(file) => Profile::read(io::BufReader::new(file)).map_err(|err| {
+            io::Error::new(err.kind(), format!("profile of {}: {}", name, err))
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Profile::default()),
+        Err(err) => Err(err),
+    }
+}
 
-    for &action_i in best_action_is.iter() {
-        println!("{:3} {:6.2}", state.my_hand[action_i].idx(), -rel_costs[action_i]);
+/// Records the decisions of other players in the finished game into their profiles and
+/// saves the profiles to the directory `dir`.
+fn record_game(
+    rules: &Rules, policy: &dyn Policy, state: &GameState, player_names: &[String],
+    profiles: &mut [Profile], dir: &OsString,
+) -> io::Result<()>
+{
+    // the decisions in a profile are only comparable under the same policy
+    for player_i in 1..state.player_count {
+        let profile = &profiles[player_i];
+        if !profile.is_compatible(policy) {
+            let msg = format!("the profile of {} was recorded with a different policy ({})",
+                player_names[player_i], profile.policy().unwrap());
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
     }
 
+    fs::create_dir_all(dir)?;
+    for player_i in 1..state.player_count {
+        let name = &player_names[player_i];
+        let profile = &mut profiles[player_i];
+        let decision_count = profile.decision_count();
+        if !profile.add_game(rules, policy, state, player_i) {
+            println!("# player {}: the game is already recorded", name);
+            continue
+        }
+
+        write_atomically(&profile_path(dir, name)?, |output| profile.write(output))?;
+        println!("# player {}: recorded {} decisions ({} in profile)", name,
+            profile.decision_count() - decision_count, profile.decision_count());
+    }
     Ok(())
 }
 
-fn read_game_state<I: io::BufRead>(input: I, rules: &Rules) -> io::Result<GameState> {
+/// Writes the file at `path` through a temporary file in the same directory, which then
+/// replaces the file, so that an interrupted write never leaves a truncated file.
+fn write_atomically<F>(path: &Path, write: F) -> io::Result<()>
+    where F: FnOnce(&mut io::BufWriter<fs::File>) -> io::Result<()>
+{
+    let tmp_path = path.with_extension("tmp");
+    let mut output = io::BufWriter::new(fs::File::create(&tmp_path)?);
+    write(&mut output)?;
+    let file = output.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
+fn profile_path(dir: &OsString, name: &str) -> io::Result<PathBuf> {
+    if name.is_empty() || !name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        let msg = format!("player name '{}' cannot be used for a profile \
+            (use only letters, digits, '-' and '_')", name);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+    Ok(Path::new(dir).join(format!("{}.txt", name)))
+}
+
+fn parse_args() -> io::Result<Options> {
+    let usage = || io::Error::new(io::ErrorKind::InvalidData, USAGE);
+    let mut input_path = None;
+    let mut objective = None;
+    let mut search = Search::Flat;
+    let mut inference = Inference::Mcmc;
+    let mut particles_path = None;
+    let mut sampling = Sampling::Joint;
+    let mut inference_policy = None;
+    let mut rollout_policy = None;
+    let mut fit_models = true;
+    let mut profiles_dir = None;
+    let mut iterations = None;
+    let mut time = None;
+    let mut margin = 3.;
+    let mut endgame_len = 2;
+    let mut thread_count = DEFAULT_THREAD_COUNT;
+    let mut seed = 0xcafef00dd15ea5e5;
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let parse_policy = |name: String| match policy::NAMES.contains(&name.as_str()) {
+        true => Ok(name),
+        false => Err(invalid(format!("unknown policy '{}' (use one of {})",
+            name, policy::NAMES.join(", ")))),
+    };
+
+    let mut args = env::args_os().skip(1);
+    while let Some(arg) = args.next() {
+        let mut flag_value = || args.next().and_then(|value| value.into_string().ok()).ok_or_else(usage);
+        match arg.to_str() {
+            Some("--objective") => {
+                let name = flag_value()?;
+                objective = Some(Objective::from_name(&name).ok_or_else(|| {
+                    let names = Objective::ALL.iter().map(|o| o.name()).collect::<Vec<_>>();
+                    invalid(format!("unknown objective '{}' (use one of {})", name, names.join(", ")))
+                })?);
+            },
+            Some("--search") => search = match flag_value()?.as_str() {
+                "flat" => Search::Flat,
+                "ismcts" => Search::Ismcts,
+                name => return Err(invalid(format!("unknown search '{}'", name))),
+            },
+            Some("--inference") => {
+                let name = flag_value()?;
+                inference = Inference::from_name(&name)
+                    .ok_or_else(|| invalid(format!("unknown inference '{}'", name)))?;
+            },
+            Some("--particles") => {
+                particles_path = Some(args.next().ok_or_else(usage)?);
+            },
+            Some("--sampling") => {
+                let name = flag_value()?;
+                sampling = Sampling::from_name(&name)
+                    .ok_or_else(|| invalid(format!("unknown sampling '{}'", name)))?;
+            },
+            Some("--inference-policy") => inference_policy = Some(parse_policy(flag_value()?)?),
+            Some("--rollout-policy") => rollout_policy = Some(parse_policy(flag_value()?)?),
+            Some("--no-fit-models") => fit_models = false,
+            Some("--profiles") => {
+                profiles_dir = Some(args.next().ok_or_else(usage)?);
+            },
+            Some("--iterations") => {
+                let value = flag_value()?;
+                iterations = Some(value.parse().ok().filter(|&count| count > 0)
+                    .ok_or_else(|| invalid(format!("bad number of iterations '{}'", value)))?);
+            },
+            Some("--time") => {
+                let value = flag_value()?;
+                let secs = value.parse::<f64>().ok().filter(|&secs| secs > 0.)
+                    .ok_or_else(|| invalid(format!("bad time '{}'", value)))?;
+                time = Some(Duration::from_secs_f64(secs));
+            },
+            Some("--margin") => {
+                let value = flag_value()?;
+                margin = value.parse::<f32>().ok().filter(|&margin| margin > 0.)
+                    .ok_or_else(|| invalid(format!("bad margin '{}'", value)))?;
+            },
+            Some("--endgame") => {
+                let value = flag_value()?;
+                endgame_len = value.parse()
+                    .map_err(|_| invalid(format!("bad number of cards '{}'", value)))?;
+            },
+            Some("--threads") => {
+                let value = flag_value()?;
+                thread_count = value.parse().ok().filter(|&count| count > 0)
+                    .ok_or_else(|| invalid(format!("bad number of threads '{}'", value)))?;
+            },
+            Some("--seed") => {
+                let value = flag_value()?;
+                seed = value.parse()
+                    .map_err(|_| invalid(format!("bad seed '{}'", value)))?;
+            },
+            Some(flag) if flag.starts_with("--") => return Err(usage()),
+            _ if input_path.is_none() => input_path = Some(arg),
+            _ => return Err(usage()),
+        }
+    }
+
+    let input_path = input_path.ok_or_else(usage)?;
+    if particles_path.is_some() && inference != Inference::Smc {
+        return Err(invalid("--particles can only be used with --inference smc".into()))
+    }
+    Ok(Options {
+        input_path, objective, search, inference, particles_path, sampling,
+        inference_policy: inference_policy.unwrap_or_else(|| "policy-1".into()),
+        rollout_policy: rollout_policy.unwrap_or_else(|| "policy-1".into()),
+        fit_models, profiles_dir, iterations, time, margin, endgame_len, thread_count, seed,
+    })
+}
+
+fn read_game_state<I: io::BufRead>(input: I)
+    -> io::Result<(Rules, PolicyTransform, GameState, Vec<String>)>
+{
+    // the rules and the transform of the policy may be changed by 'r' commands, which must
+    // precede all other commands
+    let mut rules = Rules::standard();
+    let mut transform = PolicyTransform::DEFAULT;
+    let mut rules_fixed = false;
     let mut my_hand: Option<HashSet<Card>> = None;
     let mut player_names: Option<Vec<String>> = None;
+    let mut scores: Option<Vec<usize>> = None;
     let mut past_rounds: Vec<Round> = Vec::new();
+    let mut past_round_lines: Vec<usize> = Vec::new();
     let mut current_table: Vec<Vec<Card>> = Vec::new();
+    let mut current_table_lines: Vec<usize> = Vec::new();
+    let mut pending_round: Option<Round> = None;
 
     for (line_i, line) in input.lines().enumerate() {
         let line = line?;
@@ -74,18 +488,94 @@ fn read_game_state<I: io::BufRead>(input: I, rules: &Rules) -> io::Result<GameSt
             io::Error::new(io::ErrorKind::InvalidInput, msg)
         };
 
+        let parse_usize = |word: &str| {
+            word.parse::<usize>().map_err(|_| err("could not parse integer"))
+        };
+
+        let parse_f32 = |word: &str| {
+            word.parse::<f32>().map_err(|_| err("could not parse number"))
+        };
+
         let parse_card = |idx_str: &str| {
             match idx_str.parse::<usize>() {
                 Ok(idx) if idx < rules.min_card_idx => Err(err("bad card (index too low)")),
                 Ok(idx) if idx > rules.max_card_idx => Err(err("bad card (index too high)")),
                 Err(_) => Err(err("bad card (could not parse integer)")),
-                Ok(idx) => Ok(Card::new(idx)),
+                Ok(idx) => Ok(rules.card(idx)),
+            }
+        };
+
+        // actions may be written as "card:row" to record the row chosen by a player whose
+        // card was lower than all rows
+        let parse_action = |action_str: &str| {
+            match action_str.split_once(':') {
+                None => Ok((parse_card(action_str)?, None)),
+                Some((idx_str, row_str)) => match row_str.parse::<usize>() {
+                    Ok(row) if row >= 1 && row <= rules.row_count =>
+                        Ok((parse_card(idx_str)?, Some(row - 1))),
+                    _ => Err(err("bad row choice")),
+                },
             }
         };
 
         let words = line.split_whitespace().collect::<Vec<_>>();
-        if words.len() == 0 || words[0] == "#" {
+        if words.is_empty() || words[0] == "#" {
+            continue
+        } else if words[0] == "r" {
+            if rules_fixed { return Err(err("'r' commands must precede all other commands")); }
+            match (words.get(1).cloned(), words.len()) {
+                (Some("deck"), 4) => {
+                    rules.min_card_idx = parse_usize(words[2])?;
+                    rules.max_card_idx = parse_usize(words[3])?;
+                },
+                (Some("row-len"), 3) => rules.max_row_len = parse_usize(words[2])?,
+                (Some("hand"), 3) => rules.hand_len = parse_usize(words[2])?,
+                (Some("rows"), 3) => rules.row_count = parse_usize(words[2])?,
+                (Some("scoring"), 3) => rules.costs = match words[2] {
+                    "standard" => CostTable::standard(),
+                    "flat" => CostTable::flat(1),
+                    _ => return Err(err("unknown scoring")),
+                },
+                (Some("cost"), 4) | (Some("cost"), 5) => {
+                    let costs = words[2..].iter().cloned().map(parse_usize)
+                        .collect::<io::Result<Vec<_>>>()?;
+                    let (first_idx, last_idx, cost) = match costs[..] {
+                        [idx, cost] => (idx, idx, cost),
+                        [first_idx, last_idx, cost] => (first_idx, last_idx, cost),
+                        _ => unreachable!(),
+                    };
+                    if last_idx > u8::MAX as usize || first_idx > last_idx {
+                        return Err(err("bad card range"));
+                    }
+                    if cost > u8::MAX as usize { return Err(err("cost is too high")); }
+                    for idx in first_idx..=last_idx {
+                        rules.costs.set_cost(idx, cost);
+                    }
+                },
+                (Some("match-end"), 3) => rules.match_end_score = parse_usize(words[2])?,
+                (Some("variant"), 3) => rules.variant = match words[2] {
+                    "standard" => Variant::Standard,
+                    "tactical" => Variant::Tactical,
+                    _ => return Err(err("unknown variant")),
+                },
+                (Some("policy"), 4) => match words[2] {
+                    "inverse" => transform.costs =
+                        CostTransform::Inverse { epsilon: parse_f32(words[3])? },
+                    "softmax" => transform.costs =
+                        CostTransform::Softmax { temperature: parse_f32(words[3])? },
+                    "random" => transform.random_prob = parse_f32(words[3])?,
+                    _ => return Err(err("unknown policy transform")),
+                },
+                _ => return Err(err("bad 'r' command")),
+            }
             continue
+        } else if !rules_fixed {
+            rules.check().and_then(|_| transform.check()).map_err(err)?;
+            rules_fixed = true;
+        }
+
+        if pending_round.is_some() {
+            return Err(err("no commands may follow the 'c' command"));
         } else if words[0] == "h" {
             if my_hand.is_some() { return Err(err("duplicated 'h' command")); }
             let hand = words[1..].iter().cloned().map(parse_card)
@@ -95,23 +585,71 @@ fn read_game_state<I: io::BufRead>(input: I, rules: &Rules) -> io::Result<GameSt
         } else if words[0] == "p" {
             if player_names.is_some() { return Err(err("duplicated 'p' command")); }
             if words.len() < 3 { return Err(err("too few players")); }
+            rules.restrict_deck(words.len() - 1);
+            rules.check().map_err(err)?;
+            rules.check_player_count(words.len() - 1).map_err(err)?;
+            if my_hand.iter().flatten().any(|card| card.idx() > rules.max_card_idx) {
+                return Err(err("card in my hand is not in the deck"));
+            }
             player_names = Some(words[1..].iter().map(|&n| n.to_string()).collect());
+        } else if words[0] == "s" {
+            if scores.is_some() { return Err(err("duplicated 's' command")); }
+            let player_count = player_names.as_ref().map(|ns| ns.len()).ok_or(err("missing 'p' command"))?;
+            let player_scores = words[1..].iter().cloned().map(parse_usize)
+                .collect::<io::Result<Vec<usize>>>()?;
+            if player_scores.len() != player_count { return Err(err("wrong number of scores")); }
+            if player_scores.iter().any(|&score| score >= rules.match_end_score) {
+                return Err(err("the match has already ended"));
+            }
+            scores = Some(player_scores);
         } else if words[0] == "t" {
             if words.len() < 2 { return Err(err("row cannot be empty")); }
             current_table.push(words[1..].iter().cloned().map(parse_card)
                 .collect::<io::Result<Vec<Card>>>()?);
-        } else if words[0] == "a" {
+            current_table_lines.push(line_i + 1);
+        } else if words[0] == "a" || words[0] == "c" {
             let my_hand = my_hand.as_mut().ok_or(err("missing 'h' command"))?;
             let player_count = player_names.as_ref().map(|ns| ns.len()).ok_or(err("missing 'p' command"))?;
             if current_table.len() != rules.row_count { return Err(err("wrong number of rows on table")); }
+            let table = Table::new(current_table.clone());
+            if let Some(prev_round) = past_rounds.last() {
+                check_next_table(&rules, prev_round, *past_round_lines.last().unwrap(),
+                    &table, &current_table_lines)?;
+            }
 
-            let actions = words[1..].iter().cloned().map(parse_card)
-                .collect::<io::Result<Vec<Card>>>()?;
+            let (actions, row_choices): (Vec<Card>, Vec<Option<usize>>) = words[1..].iter()
+                .cloned().map(parse_action)
+                .collect::<io::Result<Vec<_>>>()?
+                .into_iter().unzip();
             if actions.len() != player_count { return Err(err("wrong number of actions")); }
 
+            let mut engine = GameEngine::new(&rules, table.clone());
+            let outcome = engine.resolve_round(&actions, |_, player_i, _| row_choices[player_i].unwrap_or(0));
+            if outcome.players.iter().zip(row_choices.iter())
+                .any(|(outcome, row_choice)| row_choice.is_some() && !outcome.chose_row)
+            {
+                return Err(err("row choice given for a card that was not lower than all rows"));
+            }
+
             if !my_hand.remove(&actions[0]) { return Err(err("my action was not in my hand")); }
-            past_rounds.push(Round { table: Table::new(current_table), actions });
-            current_table = Vec::new();
+            let round = Round { table, actions, row_choices };
+            if words[0] == "a" {
+                past_rounds.push(round);
+                past_round_lines.push(line_i + 1);
+                current_table = Vec::new();
+                current_table_lines = Vec::new();
+            } else {
+                if GameEngine::new(&rules, round.table.clone()).legal_rows(round.actions[0]).is_empty() {
+                    return Err(err("my card is not lower than all rows"));
+                }
+                if round.actions[1..].iter().any(|card| card.idx() < round.actions[0].idx()) {
+                    return Err(err("other player played a lower card than me"));
+                }
+                if round.row_choices[0].is_some() {
+                    return Err(err("my row choice cannot be given in the 'c' command"));
+                }
+                pending_round = Some(round);
+            }
         } else {
             return Err(err("unknown command"));
         }
@@ -124,8 +662,224 @@ fn read_game_state<I: io::BufRead>(input: I, rules: &Rules) -> io::Result<GameSt
 
     let mut my_hand = my_hand.map(|h| h.into_iter().collect::<Vec<_>>()).ok_or(err("missing 'h' command"))?;
     my_hand.sort_by_key(|&card| card.idx());
-    let player_count = player_names.map(|ns| ns.len()).ok_or(err("missing 'p' command"))?;
+    let player_names = player_names.ok_or(err("missing 'p' command"))?;
+    let player_count = player_names.len();
     if current_table.len() != rules.row_count { return Err(err("wrong number of rows on table")); }
     let table = Table::new(current_table);
-    Ok(GameState { my_hand, past_rounds, table, player_count })
+    if let (None, Some(prev_round)) = (&pending_round, past_rounds.last()) {
+        check_next_table(&rules, prev_round, *past_round_lines.last().unwrap(),
+            &table, &current_table_lines)?;
+    }
+    let state = GameState { my_hand, past_rounds, table, player_count, pending_round, scores };
+    Ok((rules, transform, state, player_names))
+}
+
+/// Checks that the `next_table` is the result of resolving the `round` under the rules.
+/// If the row chosen by the player who played a card lower than all rows was not recorded,
+/// we accept any choice. `round_line` is the line with the actions of the round
+/// and `row_lines` are the lines with the rows of `next_table`.
+fn check_next_table(
+    rules: &Rules, round: &Round, round_line: usize,
+    next_table: &Table, row_lines: &[usize],
+) -> io::Result<()>
+{
+    // at most one player (the one with the lowest card) may need to choose a row, because
+    // all other cards are then higher than the row that she has chosen
+    let mut expected_tables = Vec::new();
+    for choice_row_i in 0..rules.row_count {
+        let mut engine = GameEngine::new(rules, round.table.clone());
+        let mut recorded_choice = false;
+        let outcome = engine.resolve_round(&round.actions, |_, player_i, _| {
+            recorded_choice = round.row_choices[player_i].is_some();
+            round.row_choices[player_i].unwrap_or(choice_row_i)
+        });
+        expected_tables.push(engine.table().clone());
+        if recorded_choice || !outcome.players.iter().any(|outcome| outcome.chose_row) {
+            break
+        }
+    }
+
+    let diverging_rows = |expected_table: &Table| {
+        (0..rules.row_count)
+            .filter(|&row_i| expected_table.row(row_i) != next_table.row(row_i))
+            .collect::<Vec<_>>()
+    };
+    let closest_table = expected_tables.iter()
+        .min_by_key(|expected_table| diverging_rows(expected_table).len())
+        .unwrap();
+    if let Some(&row_i) = diverging_rows(closest_table).first() {
+        let format_row = |row: &[Card]| row.iter()
+            .map(|card| card.idx().to_string())
+            .collect::<Vec<_>>().join(" ");
+        let msg = format!("{}: row {} does not follow from the round at line {} (expected '{}', got '{}')",
+            row_lines[row_i], row_i + 1, round_line,
+            format_row(closest_table.row(row_i)), format_row(next_table.row(row_i)));
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::game::{Variant};
+    use crate::policy::{Policy1, RandomPolicy};
+    use crate::test_utils::{GameBuilder, small_rules};
+    use super::*;
+
+    const GAME: &str = "
+        h 1 20 30 40 50 60 70 80 90 100
+        p me alice bob
+        t 2 4 10
+        t 48 49 68 84
+        t 51 64 72
+        t 9 22 32 47 50
+        a 20 61 80
+        t 2 4 10 20
+        t 48 49 68 84
+        t 51 64 72 80
+        t 61
+        a 1:2 62 5
+    ";
+
+    #[test]
+    fn test_check_next_table() {
+        let game = format!("{}
+            t 2 4 10 20
+            t 1 5
+            t 51 64 72 80
+            t 61 62
+        ", GAME);
+        assert!(read_game_state(game.as_bytes()).is_ok());
+
+        let game = format!("{}
+            t 2 4 10 20
+            t 1 5
+            t 51 64 72 80
+            t 61 63
+        ", GAME);
+        let err = read_game_state(game.as_bytes()).unwrap_err();
+        assert!(err.to_string().starts_with("18: row 4 does not follow from the round at line 13"),
+            "{}", err);
+
+        let game = format!("{}
+            t 2 4 10 20
+            t 48 49 68 84
+            t 1 5
+            t 61 62
+        ", GAME);
+        let err = read_game_state(game.as_bytes()).unwrap_err();
+        assert!(err.to_string().starts_with("16: row 2 does not follow"), "{}", err);
+    }
+
+    #[test]
+    fn test_record_game() {
+        let rules = small_rules(Variant::Standard, 20, 5, 2, 2);
+        let state = GameBuilder::new(&rules, 3, &[&[5], &[15]])
+            .round(&[7, 16, 11])
+            .round(&[12, 19, 6])
+            .state(&[]);
+        let names = ["me", "alice", "bob"].iter().map(|name| name.to_string()).collect::<Vec<_>>();
+        let dir = env::temp_dir().join(format!("deep_moo_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dir = dir.into_os_string();
+        let load_profiles = || (0..3)
+            .map(|player_i| match player_i {
+                0 => Profile::default(),
+                _ => load_profile(&dir, &names[player_i]).unwrap(),
+            })
+            .collect::<Vec<_>>();
+
+        // the second run finds the game in the profiles and does not record it again
+        for _ in 0..2 {
+            let mut profiles = load_profiles();
+            record_game(&rules, &Policy1::default(), &state, &names, &mut profiles, &dir).unwrap();
+        }
+        let alice_path = Path::new(&dir).join("alice.txt");
+        let alice_text = fs::read_to_string(&alice_path).unwrap();
+        assert_eq!(alice_text.lines().filter(|line| line.starts_with("game")).count(), 1);
+        assert_eq!(load_profiles()[1].decision_count(), 1);
+
+        // the profiles recorded with another policy are left untouched
+        let mut profiles = load_profiles();
+        assert!(record_game(&rules, &RandomPolicy, &state, &names, &mut profiles, &dir).is_err());
+        assert_eq!(fs::read_to_string(&alice_path).unwrap(), alice_text);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_flat_search_budget() {
+        // my 6 takes the full row, while my 16 is safe in every deal
+        let rules = small_rules(Variant::Standard, 20, 5, 2, 2);
+        let state = GameBuilder::new(&rules, 2, &[&[1, 2, 3, 4, 5], &[15]]).state(&[6, 16]);
+        let policy = Policy1::default();
+        let my_cost = |costs: &[f32]| costs[0];
+        let config = PlayoutConfig {
+            rules: &rules,
+            policy: &policy,
+            endgame_len: 2,
+            eval_costs: &my_cost,
+            deal_costs: vec![0., 0.],
+        };
+        let mut rng = Pcg64Mcg::new(42);
+        let distrib = HandsDistrib::exact(&rules, &policy, &state);
+
+        // the search stops as soon as it trusts the standard errors
+        let budget = SearchBudget { iterations: 100000, time: None };
+        let estimates = estimate_flat_rel_costs(&mut rng, &config, &state, &distrib,
+            2, budget, 3.);
+        assert_eq!(estimates.sample_count, MIN_SAMPLE_COUNT);
+        assert_eq!(estimates.best_i(), 1);
+
+        // without the margin, the time ends the search
+        let time = Some(Duration::from_millis(200));
+        let budget = SearchBudget { iterations: usize::MAX, time };
+        let start_time = Instant::now();
+        let estimates = estimate_flat_rel_costs(&mut rng, &config, &state, &distrib,
+            2, budget, f32::INFINITY);
+        assert!(estimates.sample_count > 0);
+        assert!(start_time.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn test_thread_determinism() {
+        let rules = small_rules(Variant::Tactical, 34, 5, 6, 4);
+        let state = GameBuilder::new(&rules, 3, &[&[3], &[11], &[20], &[27]])
+            .round(&[12, 21, 5])
+            .round(&[28, 13, 22])
+            .state(&[8, 17, 25, 33]);
+        let policy = Policy1::default();
+        let rel_cost = |costs: &[f32]| Objective::RelCost.eval_costs(&[0; 3], 66, costs);
+        let config = PlayoutConfig {
+            rules: &rules,
+            policy: &policy,
+            endgame_len: 2,
+            eval_costs: &rel_cost,
+            deal_costs: state.deal_costs(&rules).into_iter().map(|cost| cost as f32).collect(),
+        };
+        let budget = SearchBudget { iterations: 2000, time: None };
+        let estimate = |thread_count| {
+            let mut rng = Pcg64Mcg::new(42);
+            let distrib = HandsDistrib::estimate(&mut rng, &rules, &policy, &state,
+                Inference::Mcmc, thread_count, budget);
+            estimate_flat_rel_costs(&mut rng, &config, &state, &distrib,
+                thread_count, budget, f32::INFINITY)
+        };
+
+        // the same seed and number of threads give exactly the same results
+        let estimates = estimate(2);
+        let same_estimates = estimate(2);
+        assert_eq!(same_estimates.means, estimates.means);
+        assert_eq!(same_estimates.std_errors, estimates.std_errors);
+        assert_eq!(same_estimates.sample_count, estimates.sample_count);
+
+        // another number of threads draws other samples, but estimates the same values
+        let other_estimates = estimate(3);
+        assert_ne!(other_estimates.means, estimates.means);
+        for i in 0..estimates.means.len() {
+            let std_error = estimates.std_errors[i].hypot(other_estimates.std_errors[i]);
+            assert!((other_estimates.means[i] - estimates.means[i]).abs() < 4. * std_error,
+                "{:?} {:?}", other_estimates.means, estimates.means);
+        }
+    }
 }
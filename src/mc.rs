@@ -1,80 +1,228 @@
-use rand::{RngCore};
+use rand::{Rng, RngCore};
 
-use crate::{policy, utils};
-use crate::game::{Card, Rules, Table};
+use crate::{endgame, utils};
+use crate::game::{Card, GameEngine, Rules, Table};
+use crate::policy::{Policy};
 
+/// The parameters of the playouts, which are the same for all sampled hands.
+pub struct PlayoutConfig<'a> {
+    pub rules: &'a Rules,
+    /// The policy of all players in the playouts, including my later actions.
+    pub policy: &'a dyn Policy,
+    /// Once the hands have at most `endgame_len` cards, the rest of the deal is evaluated
+    /// exactly by the `endgame` solver.
+    pub endgame_len: usize,
+    /// Reduces the costs of all players at the end of the deal to a single number (see
+    /// `Objective::eval_costs()`), lower is better.
+    pub eval_costs: &'a (dyn Fn(&[f32]) -> f32 + Sync),
+    /// The costs that the players have collected in the past rounds of this deal (see
+    /// `GameState::deal_costs()`), which are added to the costs from the playouts.
+    pub deal_costs: Vec<f32>,
+}
+
+impl<'a> PlayoutConfig<'a> {
+    /// Samples an action from the `policy` using the uniform random number `u`.
+    pub fn sample_action(
+        &self, u: f32, table: &Table, player_count: usize, player_i: usize, hand: &[Card],
+    ) -> usize
+    {
+        if hand.len() == 1 {
+            0
+        } else {
+            let probs = self.policy.action_probs(self.rules, table, player_count, player_i, hand);
+            utils::sample_pdf_at(u, &probs)
+        }
+    }
+
+    /// Samples a row choice from the `policy` using the uniform random number `u`.
+    pub fn sample_row(
+        &self, u: f32, table: &Table, player_count: usize, player_i: usize,
+        card: Card, hand: &[Card],
+    ) -> usize
+    {
+        let probs = self.policy.row_probs(self.rules, table, player_count, player_i, card, hand);
+        utils::sample_pdf_at(u, &probs)
+    }
+}
+
+/// Estimates the costs of playing each card from my hand `hands[0]` as the first action,
+/// assuming that all other actions (including my later actions) follow the policy of the
+/// `config`. The costs of all players at the end of the playout are reduced to a single
+/// number by `config.eval_costs()`.
+///
+/// The playouts of all cards use the same random numbers (see `PlayoutRandom`), so the
+/// differences between the cards are not drowned in the noise of the other players'
+/// actions.
 pub fn estimate_policy_2_rel_costs(
-    rng: &mut dyn RngCore, rules: &Rules,
-    table: &Table, hands: &[Vec<Card>],
-    round_count: usize,
+    rng: &mut dyn RngCore, config: &PlayoutConfig,
+    table: &Table, hands: &[Vec<Card>], round_count: usize,
 ) -> Vec<f32>
 {
-    (0..hands[0].len()).into_iter()
+    let rules = config.rules;
+    if endgame::is_tractable(hands, config.endgame_len) {
+        return endgame::action_values(rules, config.policy, table, hands, &config.deal_costs,
+            config.eval_costs);
+    }
+
+    let random = PlayoutRandom::new(rng, round_count, hands.len());
+    (0..hands[0].len())
         .map(|my_first_action_i| {
-            let action_fn = |rng: &mut dyn RngCore, player_i, round_i, table: &Table, hand: &[Card]| {
+            let action_fn = |u, player_i, round_i, table: &Table, hand: &[Card]| {
                 if player_i == 0 && round_i == 0 {
                     my_first_action_i
-                } else if hand.len() == 1 {
-                    0
                 } else {
-                    let policy = policy::policy_1(rules, table, hands.len(), hand);
-                    utils::sample_pdf(rng, &policy)
+                    config.sample_action(u, table, hands.len(), player_i, hand)
                 }
             };
 
-            let costs = simulate_playout(rng, rules,
-                table.clone(), hands.to_vec(), round_count, action_fn);
-            let other_cost_mean = costs[1..].iter().sum::<f32>() / (costs.len() - 1) as f32;
-            costs[0] - other_cost_mean
+            let mut engine = GameEngine::new(rules, table.clone());
+            let costs = config.deal_costs.clone();
+            simulate_playout(config, &mut engine, hands.to_vec(), costs, &random, 0, action_fn)
         })
         .collect()
 }
 
-fn simulate_playout<F>(
-    rng: &mut dyn RngCore, rules: &Rules,
-    mut table: Table, mut hands: Vec<Vec<Card>>,
-    round_count: usize, mut action_fn: F
+/// Estimates the relative costs of eating each row when my card `actions[0]` is lower than
+/// the last cards of all rows and no other player played a lower card. The rest of the
+/// current round is resolved on the table with the replaced row and then the remaining
+/// `round_count` rounds are played out as in `estimate_policy_2_rel_costs()`, with the
+/// same random numbers for all rows.
+pub fn estimate_row_choice_rel_costs(
+    rng: &mut dyn RngCore, config: &PlayoutConfig,
+    table: &Table, actions: &[Card], hands: &[Vec<Card>], round_count: usize,
 ) -> Vec<f32>
-    where F: FnMut(&mut dyn RngCore, usize, usize, &Table, &[Card]) -> usize
 {
-    let mut costs = vec![0.; hands.len()];
-    for round_i in 0..round_count {
-        let mut actions = hands.iter_mut().enumerate().map(|(player_i, hand)| {
-            let action_i = action_fn(rng, player_i, round_i, &table, hand);
-            let action = hand.swap_remove(action_i);
-            (player_i, action)
-        }).collect::<Vec<_>>();
+    // the current round uses the random numbers of round 0, the playout starts at round 1
+    let random = PlayoutRandom::new(rng, round_count + 1, hands.len());
+    (0..config.rules.row_count)
+        .map(|my_row_i| {
+            let mut engine = GameEngine::new(config.rules, table.clone());
+            let outcome = engine.resolve_round(actions, |table, player_i, card| {
+                if player_i == 0 { my_row_i }
+                else {
+                    let u = random.row_u(0, player_i);
+                    config.sample_row(u, table, hands.len(), player_i, card, &hands[player_i])
+                }
+            });
+
+            let action_fn = |u, player_i, _round_i, table: &Table, hand: &[Card]| {
+                config.sample_action(u, table, hands.len(), player_i, hand)
+            };
+
+            let costs = config.deal_costs.iter().zip(outcome.costs())
+                .map(|(&cost, round_cost)| cost + round_cost as f32)
+                .collect();
+            simulate_playout(config, &mut engine, hands.to_vec(), costs, &random, 1, action_fn)
+        })
+        .collect()
+}
 
-        actions.sort_unstable_by_key(|(_, card)| card.idx());
+/// Random numbers for the actions and row choices of all players in all rounds of a
+/// playout. When the playouts of different candidate actions share these numbers (common
+/// random numbers), the other players tend to make the same choices in all playouts,
+/// which greatly reduces the variance of the differences between the candidates.
+struct PlayoutRandom {
+    player_count: usize,
+    action_us: Vec<f32>,
+    row_us: Vec<f32>,
+}
 
-        for (player_i, card) in actions {
-            costs[player_i] += simulate_action(rng, rules, &mut table, card);
+impl PlayoutRandom {
+    fn new(rng: &mut dyn RngCore, round_count: usize, player_count: usize) -> PlayoutRandom {
+        let len = round_count * player_count;
+        PlayoutRandom {
+            player_count,
+            action_us: (0..len).map(|_| rng.gen()).collect(),
+            row_us: (0..len).map(|_| rng.gen()).collect(),
         }
     }
-    costs
+
+    fn round_count(&self) -> usize {
+        self.action_us.len() / self.player_count
+    }
+
+    fn action_u(&self, round_i: usize, player_i: usize) -> f32 {
+        self.action_us[round_i * self.player_count + player_i]
+    }
+
+    fn row_u(&self, round_i: usize, player_i: usize) -> f32 {
+        self.row_us[round_i * self.player_count + player_i]
+    }
 }
 
-fn simulate_action(
-    rng: &mut dyn RngCore, rules: &Rules,
-    table: &mut Table, card: Card,
+/// Plays the rounds from `first_round_i` to the end of `random` with actions from
+/// `action_fn` and rows from the policy of the `config` and returns the value of
+/// `config.eval_costs()` at the end, where `costs` are the costs that the players have
+/// already collected. The remaining rounds are evaluated by the `endgame` solver as soon as
+/// it becomes tractable.
+fn simulate_playout<F>(
+    config: &PlayoutConfig, engine: &mut GameEngine,
+    mut hands: Vec<Vec<Card>>, mut costs: Vec<f32>,
+    random: &PlayoutRandom, first_round_i: usize, action_fn: F
 ) -> f32
+    where F: Fn(f32, usize, usize, &Table, &[Card]) -> usize
 {
-    let eaten_row_i =
-        if let Some(row_i) = table.match_row(card) {
-            if table.row_len(row_i) < rules.max_row_len {
-                table.push_to_row(row_i, card);
-                return 0.
-            }
-            row_i
-        } else {
-            let row_costs = (0..rules.row_count)
-                .map(|row_i| table.row_cost(row_i) as f32)
-                .collect();
-            let policy = policy::costs_to_policy(row_costs);
-            utils::sample_pdf(rng, &policy)
+    for round_i in first_round_i..random.round_count() {
+        if endgame::is_tractable(&hands, config.endgame_len) {
+            return endgame::value(config.rules, config.policy, engine.table(), &hands, &costs,
+                config.eval_costs);
+        }
+
+        // we keep the order of cards in the hands, so that the same random number tends to
+        // select the same card in the playouts of all candidates
+        let actions = hands.iter_mut().enumerate().map(|(player_i, hand)| {
+            let u = random.action_u(round_i, player_i);
+            let action_i = action_fn(u, player_i, round_i - first_round_i, engine.table(), hand);
+            hand.remove(action_i)
+        }).collect::<Vec<_>>();
+
+        let player_count = hands.len();
+        let outcome = engine.resolve_round(&actions, |table, player_i, card| {
+            let u = random.row_u(round_i, player_i);
+            config.sample_row(u, table, player_count, player_i, card, &hands[player_i])
+        });
+        for (cost, round_cost) in costs.iter_mut().zip(outcome.costs()) {
+            *cost += round_cost as f32;
+        }
+    }
+    (config.eval_costs)(&costs)
+}
+
+#[cfg(test)]
+mod test {
+    use rand_pcg::{Pcg64Mcg};
+    use crate::game::{Variant};
+    use crate::objective::{Objective};
+    use crate::policy::{RandomPolicy};
+    use crate::test_utils::{GameBuilder, make_cards, small_rules};
+    use super::*;
+
+    #[test]
+    fn test_deal_costs() {
+        // bob eats the row 30 (cost 3) with his 11 in the first round: with his penalty, my 2
+        // wins the deal for sure, while without it my 9 would have a better chance to win
+        let rules = small_rules(Variant::Standard, 30, 3, 3, 2);
+        let state = GameBuilder::new(&rules, 2, &[&[30], &[15]])
+            .round(&[14, 11])
+            .state(&[2, 9]);
+        let hands = vec![state.my_hand.clone(), make_cards(&rules, &[6, 20])];
+        let win_deal = |costs: &[f32]| Objective::WinDeal.eval_costs(&[0, 0], 66, costs);
+        let mut config = PlayoutConfig {
+            rules: &rules,
+            policy: &RandomPolicy,
+            endgame_len: 2,
+            eval_costs: &win_deal,
+            deal_costs: vec![0., 0.],
         };
 
-    let cost = table.row_cost(eaten_row_i);
-    table.replace_row(eaten_row_i, card);
-    cost as f32
+        let mut rng = Pcg64Mcg::new(42);
+        let rel_costs = estimate_policy_2_rel_costs(&mut rng, &config, &state.table, &hands, 2);
+        assert!(rel_costs[1] < rel_costs[0]);
+
+        assert_eq!(state.deal_costs(&rules), vec![0, 3]);
+        config.deal_costs = vec![0., 3.];
+        let rel_costs = estimate_policy_2_rel_costs(&mut rng, &config, &state.table, &hands, 2);
+        assert_eq!(rel_costs[0], -1.);
+        assert!(rel_costs[0] < rel_costs[1]);
+    }
 }
@@ -0,0 +1,207 @@
+use std::time::{Instant};
+use rand::{Rng, RngCore};
+
+use crate::{distrib, utils};
+use crate::game::{Card, GameState, Rules};
+use crate::policy::{Policy};
+use crate::utils::{SearchBudget};
+
+/// The fraction of steps at the beginning of every chain that are discarded (burn-in).
+const BURN_IN_FRACTION: f32 = 0.25;
+
+/// After the burn-in, a sample is recorded after every `THINNING` steps of a chain.
+const THINNING: usize = 10;
+
+/// Samples the full hands of all owners (in the format of `distrib::sample_hands_uniform()`)
+/// from the posterior distribution Pr(hands | state), assuming that other players follow
+/// the `policy`. Every thread runs an independent Metropolis-Hastings chain that starts from
+/// a uniformly sampled deal and swaps unknown cards between two different owners (other
+/// players or the deck) in every step. The steps in the `budget` are split among the
+/// chains. Returns the samples and the acceptance rate of the proposed swaps.
+pub fn sample_hands(
+    rng: &mut dyn RngCore, rules: &Rules, policy: &dyn Policy, state: &GameState,
+    thread_count: usize, budget: SearchBudget,
+) -> (Vec<Vec<Vec<Card>>>, f32)
+{
+    let (known_hands, unknown_cards) = distrib::split_cards(rules, state);
+    let start_time = Instant::now();
+    let chains = utils::run_parallel(rng, thread_count, budget.iterations, |rng, steps| {
+        let step_count = steps.len();
+        let mut unknown_cards = unknown_cards.to_vec();
+        let hands = distrib::sample_hands_uniform(rng, rules, &known_hands, &mut unknown_cards);
+        let round_count = state.observed_rounds().count();
+        let mut chain = Chain::new(rules, policy, state, round_count, hands);
+        // after the burn-in, we keep every `THINNING`-th state of the chain
+        let mut next_sample_i = (step_count as f32 * BURN_IN_FRACTION) as usize;
+        let mut samples = Vec::new();
+        for step_i in 0..step_count {
+            let check_time = step_i % 100 == 0;
+            if check_time && budget.time.is_some_and(|time| start_time.elapsed() >= time) {
+                break
+            }
+            chain.step(rng, rules, policy, state);
+            if step_i == next_sample_i {
+                samples.push(chain.hands.clone());
+                next_sample_i += THINNING;
+            }
+        }
+        if samples.is_empty() {
+            samples.push(chain.hands.clone());
+        }
+        (samples, chain.accepted_count, chain.proposed_count)
+    });
+
+    let mut samples = Vec::new();
+    let mut accepted_count = 0;
+    let mut proposed_count = 0;
+    for (chain_samples, chain_accepted_count, chain_proposed_count) in chains.into_iter() {
+        samples.extend(chain_samples);
+        accepted_count += chain_accepted_count;
+        proposed_count += chain_proposed_count;
+    }
+    let acceptance_rate =
+        if proposed_count == 0 { 1. }
+        else { accepted_count as f32 / proposed_count as f32 };
+    (samples, acceptance_rate)
+}
+
+/// The state of a Metropolis-Hastings chain, which samples from the posterior given the
+/// first `round_count` observed rounds.
+pub struct Chain {
+    /// The full hands of all owners, owner 0 is the deck.
+    pub hands: Vec<Vec<Card>>,
+    round_count: usize,
+    /// The positions `(owner_i, card_i)` of the unknown cards in `hands`, grouped by owner.
+    positions: Vec<(usize, usize)>,
+    /// The number of unknown cards of every owner.
+    owner_counts: Vec<usize>,
+    /// The index of the first position of every owner in `positions`.
+    owner_begins: Vec<usize>,
+    /// `distrib::calc_player_log_prob()` of every player for the current `hands`.
+    player_log_probs: Vec<f32>,
+    pub accepted_count: usize,
+    pub proposed_count: usize,
+}
+
+impl Chain {
+    /// Starts the chain from the given full hands, where the first `round_count` cards of
+    /// every player are the cards played in the observed rounds.
+    pub fn new(
+        rules: &Rules, policy: &dyn Policy, state: &GameState,
+        round_count: usize, hands: Vec<Vec<Card>>,
+    ) -> Chain
+    {
+        let mut positions = Vec::new();
+        let mut owner_counts = Vec::new();
+        let mut owner_begins = Vec::new();
+        for (owner_i, hand) in hands.iter().enumerate() {
+            let known_len = if owner_i == 0 { 0 } else { round_count };
+            owner_begins.push(positions.len());
+            owner_counts.push(hand.len() - known_len);
+            positions.extend((known_len..hand.len()).map(|card_i| (owner_i, card_i)));
+        }
+
+        let player_log_probs = (0..hands.len())
+            .map(|player_i| {
+                if player_i == 0 { 0. }
+                else {
+                    distrib::calc_player_log_prob(rules, policy, state, round_count,
+                        player_i, &hands[player_i])
+                }
+            })
+            .collect();
+
+        Chain {
+            hands, round_count, positions, owner_counts, owner_begins, player_log_probs,
+            accepted_count: 0, proposed_count: 0,
+        }
+    }
+
+    /// Proposes to swap two unknown cards of different owners and accepts the swap with
+    /// the Metropolis-Hastings probability. The proposal is symmetric: we select a random
+    /// position and then a random position of another owner, so the reverse swap of the
+    /// same two positions has the same probability.
+    pub fn step(
+        &mut self, rng: &mut dyn RngCore, rules: &Rules,
+        policy: &dyn Policy, state: &GameState,
+    ) {
+        if self.positions.is_empty() {
+            return
+        }
+        let pos_a = rng.gen_range(0, self.positions.len());
+        let owner_a = self.positions[pos_a].0;
+        let other_count = self.positions.len() - self.owner_counts[owner_a];
+        if other_count == 0 {
+            return
+        }
+        let mut pos_b = rng.gen_range(0, other_count);
+        if pos_b >= self.owner_begins[owner_a] {
+            pos_b += self.owner_counts[owner_a];
+        }
+
+        let (owner_a, card_a) = self.positions[pos_a];
+        let (owner_b, card_b) = self.positions[pos_b];
+        self.swap(owner_a, card_a, owner_b, card_b);
+        let new_log_probs = [owner_a, owner_b].iter()
+            .map(|&owner_i| {
+                if owner_i == 0 { 0. }
+                else {
+                    distrib::calc_player_log_prob(rules, policy, state, self.round_count,
+                        owner_i, &self.hands[owner_i])
+                }
+            })
+            .collect::<Vec<_>>();
+        let log_ratio = new_log_probs[0] - self.player_log_probs[owner_a]
+            + new_log_probs[1] - self.player_log_probs[owner_b];
+
+        self.proposed_count += 1;
+        if log_ratio >= 0. || rng.gen::<f32>().ln() < log_ratio {
+            self.player_log_probs[owner_a] = new_log_probs[0];
+            self.player_log_probs[owner_b] = new_log_probs[1];
+            self.accepted_count += 1;
+        } else {
+            self.swap(owner_a, card_a, owner_b, card_b);
+        }
+    }
+
+    fn swap(&mut self, owner_a: usize, card_a: usize, owner_b: usize, card_b: usize) {
+        let card = self.hands[owner_a][card_a];
+        self.hands[owner_a][card_a] = self.hands[owner_b][card_b];
+        self.hands[owner_b][card_b] = card;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand_pcg::{Pcg64Mcg};
+    use crate::game::{Variant};
+    use crate::policy::{Policy1};
+    use crate::test_utils::{GameBuilder, deal_distance, exact_deal_probs, small_rules};
+    use super::*;
+
+    #[test]
+    fn test_sample_hands() {
+        // all cards are dealt: the initial table was 3 | 8, I had 1 4 9 13 and the
+        // opponents played 11 and 6, so the unknown cards 2 5 7 10 12 14 are split between
+        // the opponents in one of 20 ways, which are not equally likely
+        let rules = small_rules(Variant::Tactical, 14, 2, 4, 2);
+        let state = GameBuilder::new(&rules, 3, &[&[3], &[8]])
+            .round(&[4, 11, 6])
+            .state(&[1, 9, 13]);
+        let exact_probs = exact_deal_probs(&rules, &Policy1::default(), &state);
+        assert_eq!(exact_probs.len(), 20);
+        // the posterior is far enough from the uniform distribution to tell them apart
+        let uniform_distance = exact_probs.values()
+            .map(|&exact_prob| 0.5 * (exact_prob - 1. / 20.).abs())
+            .sum::<f32>();
+        assert!(uniform_distance > 0.05);
+
+        let mut rng = Pcg64Mcg::new(42);
+        let budget = SearchBudget { iterations: 200000, time: None };
+        let (samples, acceptance_rate) = sample_hands(&mut rng, &rules, &Policy1::default(),
+            &state, 2, budget);
+        assert!(acceptance_rate > 0.1);
+        let distance = deal_distance(&exact_probs, samples.into_iter().map(|hands| (hands, 1.)));
+        assert!(distance < 0.03, "{}", distance);
+    }
+}
@@ -0,0 +1,169 @@
+use crate::utils;
+
+/// The objective that we optimize when evaluating the actions.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Objective {
+    /// Minimize my cost relative to the mean cost of other players.
+    RelCost,
+    /// Minimize my cost.
+    Cost,
+    /// Maximize the probability that I do not finish last in this deal.
+    NotLast,
+    /// Maximize the probability that I win this deal.
+    WinDeal,
+    /// Minimize the difference between my score and the score of the leader.
+    LeaderGap,
+    /// Maximize the probability that I win the match.
+    WinMatch,
+}
+
+impl Objective {
+    pub const ALL: [Objective; 6] = [
+        Objective::RelCost, Objective::Cost, Objective::NotLast,
+        Objective::WinDeal, Objective::LeaderGap, Objective::WinMatch,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Objective::RelCost => "rel-cost",
+            Objective::Cost => "cost",
+            Objective::NotLast => "not-last",
+            Objective::WinDeal => "win-deal",
+            Objective::LeaderGap => "leader-gap",
+            Objective::WinMatch => "win-match",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Objective> {
+        Objective::ALL.iter().cloned().find(|objective| objective.name() == name)
+    }
+
+    /// Reduces the costs of all players in this deal to a single value, lower is better
+    /// (probabilities are negated). `scores` are the scores of players from the previous
+    /// deals and the match ends when somebody reaches `end_score`.
+    pub fn eval_costs(&self, scores: &[usize], end_score: usize, costs: &[f32]) -> f32 {
+        match self {
+            Objective::RelCost => rel_cost(costs),
+            Objective::Cost => costs[0],
+            Objective::NotLast => {
+                let max_cost = costs.iter().fold(0., |x, &y| f32::max(x, y));
+                -(1. - share_prob(costs, max_cost))
+            },
+            Objective::WinDeal => {
+                let min_cost = costs.iter().fold(f32::INFINITY, |x, &y| f32::min(x, y));
+                -share_prob(costs, min_cost)
+            },
+            Objective::LeaderGap => {
+                let final_scores = final_scores(scores, costs);
+                let leader_score = final_scores[1..].iter().fold(f32::INFINITY, |x, &y| f32::min(x, y));
+                final_scores[0] - leader_score
+            },
+            Objective::WinMatch => match_win_cost(scores, end_score, costs),
+        }
+    }
+}
+
+/// Returns my cost relative to the mean cost of other players.
+fn rel_cost(costs: &[f32]) -> f32 {
+    let other_cost_mean = costs[1..].iter().sum::<f32>() / (costs.len() - 1) as f32;
+    costs[0] - other_cost_mean
+}
+
+/// Returns my share of the given `value` among players whose value is the same, or zero if
+/// my value is different.
+fn share_prob(values: &[f32], value: f32) -> f32 {
+    if values[0] != value {
+        0.
+    } else {
+        1. / values.iter().filter(|&&x| x == value).count() as f32
+    }
+}
+
+fn final_scores(scores: &[usize], costs: &[f32]) -> Vec<f32> {
+    scores.iter().zip(costs.iter())
+        .map(|(&score, &cost)| score as f32 + cost)
+        .collect()
+}
+
+/// The scale of the differences in scores in the heuristic win probabilities of
+/// `match_win_cost()`, relative to the square root of the score that remains to the end of
+/// the match. The constant was chosen by hand, so that at the start of a match to 66
+/// points, a lead of about 20 points makes a player e times more likely to win.
+const MATCH_WIN_TEMPERATURE: f32 = 2.5;
+
+/// Returns my negated probability of winning the match, given the scores of players before
+/// this deal and their costs in this deal. The match ends when somebody reaches
+/// `end_score` and the player with the lowest score wins (players with the same score share
+/// the win).
+///
+/// If the match does not end in this deal, we only have a heuristic estimate: the lower
+/// the score, the higher the chance to win, but the differences matter less when the
+/// players are far from `end_score`.
+fn match_win_cost(scores: &[usize], end_score: usize, costs: &[f32]) -> f32 {
+    let final_scores = final_scores(scores, costs);
+    let max_score = final_scores.iter().fold(0., |x, &y| f32::max(x, y));
+    let min_score = final_scores.iter().fold(f32::INFINITY, |x, &y| f32::min(x, y));
+
+    let remaining_score = end_score as f32 - max_score;
+    let win_prob =
+        if remaining_score <= 0. {
+            share_prob(&final_scores, min_score)
+        } else {
+            let temperature = MATCH_WIN_TEMPERATURE * remaining_score.sqrt();
+            let mut win_probs = final_scores.iter()
+                .map(|score| (-(score - min_score) / temperature).exp())
+                .collect::<Vec<_>>();
+            utils::normalize_pdf(&mut win_probs);
+            win_probs[0]
+        };
+    -win_prob
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn eval(objective: Objective, costs: &[f32]) -> f32 {
+        objective.eval_costs(&vec![0; costs.len()], 66, costs)
+    }
+
+    #[test]
+    fn test_deal_objectives() {
+        assert_eq!(eval(Objective::RelCost, &[3., 1., 5.]), 0.);
+        assert_eq!(eval(Objective::RelCost, &[0., 2., 4.]), -3.);
+        assert_eq!(eval(Objective::Cost, &[3., 1., 5.]), 3.);
+
+        assert_eq!(eval(Objective::NotLast, &[1., 5., 3.]), -1.);
+        assert_eq!(eval(Objective::NotLast, &[5., 1., 3.]), 0.);
+        assert_eq!(eval(Objective::WinDeal, &[1., 5., 3.]), -1.);
+        assert_eq!(eval(Objective::WinDeal, &[3., 1., 5.]), 0.);
+
+        // players with the same cost share the win or the last place
+        assert_eq!(eval(Objective::NotLast, &[5., 5., 1.]), -0.5);
+        assert_eq!(eval(Objective::WinDeal, &[1., 1., 3.]), -0.5);
+        assert_eq!(eval(Objective::WinDeal, &[2., 2., 2.]), -1. / 3.);
+        assert_eq!(eval(Objective::NotLast, &[2., 2., 2.]), -(1. - 1. / 3.));
+    }
+
+    #[test]
+    fn test_match_objectives() {
+        let leader_gap = Objective::LeaderGap.eval_costs(&[10, 20, 5], 66, &[3., 0., 1.]);
+        assert_eq!(leader_gap, 13. - 6.);
+
+        // the match ends when somebody reaches the end score exactly
+        let win_match = |scores: &[usize], costs: &[f32]|
+            Objective::WinMatch.eval_costs(scores, 66, costs);
+        assert_eq!(win_match(&[60, 60], &[0., 6.]), -1.);
+        assert_eq!(win_match(&[60, 60], &[6., 0.]), 0.);
+        assert_eq!(win_match(&[60, 60], &[6., 6.]), -0.5);
+        assert_eq!(win_match(&[60, 60], &[0., 10.]), -1.);
+
+        // one point before the end, we only have the heuristic estimate
+        let win_prob = -win_match(&[60, 60], &[0., 5.]);
+        assert!(0.5 < win_prob && win_prob < 1.);
+        assert_eq!(win_match(&[30, 30], &[2., 2.]), -0.5);
+
+        // the same lead matters more when the end of the match is closer
+        assert!(win_match(&[50, 55], &[0., 0.]) < win_match(&[10, 15], &[0., 0.]));
+    }
+}
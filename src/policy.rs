@@ -1,27 +1,325 @@
+use std::{cmp, fmt};
+
 use crate::game::{Card, Rules, Table};
 use crate::utils;
 
+/// A model of how players choose their cards and rows. We use it both to infer the hands of
+/// other players from the cards that they played and to play out the rest of the deal. The
+/// model may treat every player differently (player 0 is me).
+pub trait Policy: Sync {
+    /// Returns the name of the policy (see `from_name()`) with its parameters, which
+    /// identifies the probabilities that the policy gives.
+    fn name(&self) -> String;
+
+    /// Returns a normalized pdf over the cards in the `hand` of the player.
+    fn action_probs(
+        &self, rules: &Rules, table: &Table,
+        player_count: usize, player_i: usize, hand: &[Card],
+    ) -> Vec<f32>;
+
+    /// Returns a normalized pdf over the rows that the player may eat when her `card` is
+    /// lower than all rows. The `hand` contains the remaining cards of the player.
+    fn row_probs(
+        &self, rules: &Rules, table: &Table,
+        player_count: usize, player_i: usize, card: Card, hand: &[Card],
+    ) -> Vec<f32>;
+}
+
+/// The names of the policies accepted by `from_name()`.
+pub const NAMES: [&str; 2] = ["policy-1", "random"];
+
+/// Returns the policy with the given name. The `transform` configures `Policy1`.
+pub fn from_name(name: &str, transform: PolicyTransform) -> Option<Box<dyn Policy>> {
+    match name {
+        "policy-1" => Some(Box::new(Policy1::new(transform))),
+        "random" => Some(Box::new(RandomPolicy)),
+        _ => None,
+    }
+}
+
+/// The "reasonable" player given by `policy_1()` and `row_policy_1()`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Policy1 {
+    /// How the player turns the costs of her actions into probabilities.
+    pub transform: PolicyTransform,
+}
+
+impl Policy1 {
+    pub fn new(transform: PolicyTransform) -> Policy1 {
+        Policy1 { transform }
+    }
+}
+
+impl Policy for Policy1 {
+    fn name(&self) -> String {
+        format!("policy-1 {}", self.transform)
+    }
+
+    fn action_probs(
+        &self, rules: &Rules, table: &Table,
+        player_count: usize, _player_i: usize, hand: &[Card],
+    ) -> Vec<f32>
+    {
+        policy_1(rules, &self.transform, table, player_count, hand)
+    }
+
+    fn row_probs(
+        &self, rules: &Rules, table: &Table,
+        player_count: usize, _player_i: usize, card: Card, hand: &[Card],
+    ) -> Vec<f32>
+    {
+        row_policy_1(rules, &self.transform, table, player_count, card, hand)
+    }
+}
+
+/// A player who plays uniformly random cards and eats uniformly random rows.
+#[derive(Debug, Copy, Clone)]
+pub struct RandomPolicy;
+
+impl Policy for RandomPolicy {
+    fn name(&self) -> String {
+        "random".into()
+    }
+
+    fn action_probs(
+        &self, _rules: &Rules, _table: &Table,
+        _player_count: usize, _player_i: usize, hand: &[Card],
+    ) -> Vec<f32>
+    {
+        vec![1. / hand.len() as f32; hand.len()]
+    }
+
+    fn row_probs(
+        &self, rules: &Rules, _table: &Table,
+        _player_count: usize, _player_i: usize, _card: Card, _hand: &[Card],
+    ) -> Vec<f32>
+    {
+        vec![1. / rules.row_count as f32; rules.row_count]
+    }
+}
+
+/// The parameters that adjust a base policy to the style of a particular player.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PlayerModel {
+    /// The probabilities of the base policy are raised to `1 / temperature`, so a player
+    /// with a low temperature sticks to the best actions and a player with a high
+    /// temperature plays more randomly.
+    pub temperature: f32,
+    /// The probability of every card is multiplied by `exp(card_bias * x)`, where `x` goes
+    /// from 0 for the lowest card in the deck to 1 for the highest card. A player with a
+    /// positive bias gets rid of her high cards early, a player with a negative bias gets
+    /// rid of her low cards.
+    pub card_bias: f32,
+}
+
+impl PlayerModel {
+    /// The model that does not change the base policy.
+    pub const NEUTRAL: PlayerModel = PlayerModel { temperature: 1., card_bias: 0. };
+
+    /// Adjusts the `probs` of actions (the cards in the `hand`) from the base policy.
+    pub fn adjust_action_probs(&self, rules: &Rules, hand: &[Card], probs: &mut [f32]) {
+        if *self == PlayerModel::NEUTRAL { return }
+        for (prob, &card) in probs.iter_mut().zip(hand.iter()) {
+            *prob = self.adjust_card_prob(*prob, PlayerModel::card_position(rules, card));
+        }
+        utils::normalize_pdf(probs);
+    }
+
+    /// Adjusts the probability of a card with the given `card_position()` (without
+    /// normalization).
+    pub fn adjust_card_prob(&self, prob: f32, position: f32) -> f32 {
+        prob.powf(1. / self.temperature) * (self.card_bias * position).exp()
+    }
+
+    /// Returns the position of the card in the deck, from 0 for the lowest card to 1 for
+    /// the highest card (the only card of a deck with a single card is in the middle).
+    pub fn card_position(rules: &Rules, card: Card) -> f32 {
+        if rules.max_card_idx == rules.min_card_idx {
+            return 0.5
+        }
+        (card.idx() - rules.min_card_idx) as f32 / (rules.max_card_idx - rules.min_card_idx) as f32
+    }
+
+    /// Adjusts the `probs` of rows from the base policy.
+    pub fn adjust_row_probs(&self, probs: &mut [f32]) {
+        if self.temperature == 1. { return }
+        probs.iter_mut().for_each(|prob| *prob = prob.powf(1. / self.temperature));
+        utils::normalize_pdf(probs);
+    }
+}
+
+/// A base policy adjusted by a `PlayerModel` of every player.
+pub struct ModeledPolicy {
+    base: Box<dyn Policy>,
+    models: Vec<PlayerModel>,
+}
+
+impl ModeledPolicy {
+    pub fn new(base: Box<dyn Policy>, models: Vec<PlayerModel>) -> ModeledPolicy {
+        ModeledPolicy { base, models }
+    }
+
+    pub fn base(&self) -> &dyn Policy {
+        &*self.base
+    }
+}
+
+impl Policy for ModeledPolicy {
+    fn name(&self) -> String {
+        let models = self.models.iter()
+            .map(|model| format!(" {}:{}", model.temperature, model.card_bias))
+            .collect::<String>();
+        format!("{} models{}", self.base.name(), models)
+    }
+
+    fn action_probs(
+        &self, rules: &Rules, table: &Table,
+        player_count: usize, player_i: usize, hand: &[Card],
+    ) -> Vec<f32>
+    {
+        let mut probs = self.base.action_probs(rules, table, player_count, player_i, hand);
+        self.models[player_i].adjust_action_probs(rules, hand, &mut probs);
+        probs
+    }
+
+    fn row_probs(
+        &self, rules: &Rules, table: &Table,
+        player_count: usize, player_i: usize, card: Card, hand: &[Card],
+    ) -> Vec<f32>
+    {
+        let mut probs = self.base.row_probs(rules, table, player_count, player_i, card, hand);
+        self.models[player_i].adjust_row_probs(&mut probs);
+        probs
+    }
+}
+
 /// Calculates a basic first-order policy that a "reasonable" actor may play in the given table
 /// situation and with the given hand. Returns a normalized pdf where values correspond to actions
 /// (cards) from the hand.
-pub fn policy_1(rules: &Rules, table: &Table, player_count: usize, hand: &[Card]) -> Vec<f32> {
-    let costs = hand.iter().map(|&card| policy_1_q(rules, table, player_count, hand.len(), card)).collect();
-    costs_to_policy(costs)
+pub fn policy_1(
+    rules: &Rules, transform: &PolicyTransform,
+    table: &Table, player_count: usize, hand: &[Card],
+) -> Vec<f32>
+{
+    let costs = hand.iter().map(|&card| policy_1_q(rules, table, player_count, hand, card)).collect();
+    transform.apply(costs)
+}
+
+/// Calculates a first-order policy for choosing the row that a "reasonable" actor eats when
+/// her card is lower than all rows. Besides the cost of the row, the actor considers how
+/// the table with the replaced row suits the rest of her hand. Returns a normalized pdf
+/// over the rows.
+pub fn row_policy_1(
+    rules: &Rules, transform: &PolicyTransform,
+    table: &Table, player_count: usize, card: Card, hand: &[Card],
+) -> Vec<f32>
+{
+    let costs = (0..rules.row_count).map(|row_i| {
+        let mut table = table.clone();
+        let row_cost = table.row_cost(row_i) as f32;
+        table.replace_row(row_i, card);
+
+        let hand_cost = hand.iter()
+            .map(|&card| policy_1_q(rules, &table, player_count, hand, card))
+            .sum::<f32>() / cmp::max(hand.len(), 1) as f32;
+        row_cost + hand_cost
+    }).collect();
+    transform.apply(costs)
+}
+
+/// How a "reasonable" player turns the costs of her actions into probabilities.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PolicyTransform {
+    pub costs: CostTransform,
+    /// The probability that the player ignores the costs and picks a uniformly random
+    /// action, so that even a terrible action has a non-negligible probability.
+    pub random_prob: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CostTransform {
+    /// The probability is proportional to `1 / (epsilon + cost)`. Actions with zero cost
+    /// get a lot of probability when `epsilon` is small.
+    Inverse { epsilon: f32 },
+    /// The probability is proportional to `exp(-cost / temperature)`, so the probabilities
+    /// depend only on the differences of costs.
+    Softmax { temperature: f32 },
+}
+
+impl Default for PolicyTransform {
+    fn default() -> PolicyTransform {
+        PolicyTransform::DEFAULT
+    }
+}
+
+impl PolicyTransform {
+    pub const DEFAULT: PolicyTransform = PolicyTransform {
+        costs: CostTransform::Inverse { epsilon: 0.02 },
+        random_prob: 0.,
+    };
+
+    /// Converts the costs of actions to a normalized pdf.
+    pub fn apply(&self, mut xs: Vec<f32>) -> Vec<f32> {
+        match self.costs {
+            CostTransform::Inverse { epsilon } =>
+                xs.iter_mut().for_each(|x| *x = 1. / (epsilon + *x)),
+            CostTransform::Softmax { temperature } => {
+                let min_x = xs.iter().cloned().fold(f32::INFINITY, f32::min);
+                xs.iter_mut().for_each(|x| *x = (-(*x - min_x) / temperature).exp());
+            },
+        }
+        utils::normalize_pdf(&mut xs);
+
+        if self.random_prob > 0. {
+            let uniform_prob = 1. / xs.len() as f32;
+            xs.iter_mut().for_each(|x| {
+                *x = (1. - self.random_prob) * *x + self.random_prob * uniform_prob;
+            });
+        }
+        xs
+    }
+
+    /// Checks that the parameters are valid.
+    pub fn check(&self) -> Result<(), &'static str> {
+        match self.costs {
+            CostTransform::Inverse { epsilon } =>
+                if !(epsilon.is_finite() && epsilon > 0.) {
+                    return Err("the epsilon of the policy must be positive");
+                },
+            CostTransform::Softmax { temperature } =>
+                if !(temperature.is_finite() && temperature > 0.) {
+                    return Err("the temperature of the policy must be positive");
+                },
+        }
+        if !(0. ..=1.).contains(&self.random_prob) {
+            return Err("the probability of random actions must be between 0 and 1");
+        }
+        Ok(())
+    }
 }
 
-/// Converts action costs to a "reasonable" policy.
-pub fn costs_to_policy(mut xs: Vec<f32>) -> Vec<f32> {
-    xs.iter_mut().for_each(|x| *x = 1. / (0.02 + *x));
-    utils::normalize_pdf(&mut xs);
-    xs
+impl fmt::Display for PolicyTransform {
+    /// Formats the transform like the 'r policy' lines, for example "softmax 2 random 0.1".
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.costs {
+            CostTransform::Inverse { epsilon } => write!(f, "inverse {}", epsilon)?,
+            CostTransform::Softmax { temperature } => write!(f, "softmax {}", temperature)?,
+        }
+        write!(f, " random {}", self.random_prob)
+    }
 }
 
 /// Estimates the action value of the given card at the given table situation.
 /// Our basic assumption is that other players play completely at random. While this is
 /// not in general true in practice for human players, it still provides a basic for a
 /// reasonable strategy.
-fn policy_1_q(rules: &Rules, table: &Table, player_count: usize, hand_len: usize, card: Card) -> f32 {
-    let free_card_count = rules.card_count() - table.card_count() - hand_len;
+fn policy_1_q(rules: &Rules, table: &Table, player_count: usize, hand: &[Card], card: Card) -> f32 {
+    let hand_len = hand.len();
+    // `free_card_count` is the number of cards that the other players may play: when all
+    // cards are dealt, these are exactly the cards in their hands.
+    let free_card_count =
+        if rules.all_cards_dealt() { (player_count - 1) * hand_len }
+        else { rules.card_count() - table.card_count() - hand_len };
 
     if let Some(row_i) = table.match_row(card) {
         // Case 1: this card will be added to row_i (we ignore the possibility that other
@@ -44,11 +342,15 @@ fn policy_1_q(rules: &Rules, table: &Table, player_count: usize, hand_len: usize
             return row_cost;
         }
 
-        // Estimate the cost of this row once it is full
-        let cost = row_cost + slack as f32 * Card::mean_cost(last_idx + 1, card.idx());
+        // Estimate the cost of this row once it is full: the `slack` cards that fill the
+        // row come from the "gap", so their expected cost is the mean cost of the cards in
+        // the gap that we have not seen on the table or in our hand.
+        let gap_cost = rules.mean_cost(last_idx + 1, card.idx(),
+            table.cards().chain(hand.iter()));
+        let cost = row_cost + slack as f32 * gap_cost;
         // Estimate the probability that a card randomly played by other player hits the
         // "gap" between the last card in the row and our card.
-        let gap_prob = gap as f32 / free_card_count as f32;
+        let gap_prob = f32::min(gap as f32 / free_card_count as f32, 1.);
         // Calculate the probability that exactly `slack` cards from other players fall
         // into the "gap", forcing us to eat this row.
         let hit_prob = utils::binom_pdf(player_count - 1, slack, gap_prob);
@@ -66,7 +368,7 @@ fn policy_1_q(rules: &Rules, table: &Table, player_count: usize, hand_len: usize
         let gap = card.idx() - rules.min_card_idx;
         // Estimate the probability that a player may hit the "gap", playing even smaller
         // card than us and saving us from "under-eating".
-        let gap_prob = gap as f32 / free_card_count as f32;
+        let gap_prob = f32::min(gap as f32 / free_card_count as f32, 1.);
         // Estimate the probability that no player hits the "gap", so we will be forced to
         // "under-eat".
         let hit_prob = (1. - gap_prob).powi(player_count as i32 - 1);
@@ -74,3 +376,33 @@ fn policy_1_q(rules: &Rules, table: &Table, player_count: usize, hand_len: usize
         hit_prob * cost
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_policy_transform() {
+        let costs = vec![0., 1., 3.];
+        let inverse = PolicyTransform::DEFAULT.apply(costs.clone());
+        assert!(inverse[0] > 0.95);
+
+        // the softmax depends only on the differences of costs
+        let softmax = PolicyTransform {
+            costs: CostTransform::Softmax { temperature: 1. },
+            random_prob: 0.,
+        };
+        let probs = softmax.apply(costs.clone());
+        let shifted_probs = softmax.apply(costs.iter().map(|x| x + 10.).collect());
+        for (prob, shifted_prob) in probs.iter().zip(shifted_probs.iter()) {
+            assert!((prob - shifted_prob).abs() < 1e-6);
+        }
+        assert!((probs[0] / probs[1] - 1f32.exp()).abs() < 1e-4);
+
+        // every action has at least the share of the random actions
+        let mixture = PolicyTransform { random_prob: 0.3, ..PolicyTransform::DEFAULT };
+        let probs = mixture.apply(costs);
+        assert!(probs.iter().all(|&prob| prob >= 0.1 - 1e-6));
+        assert!((probs.iter().sum::<f32>() - 1.).abs() < 1e-6);
+    }
+}
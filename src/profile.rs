@@ -0,0 +1,242 @@
+use std::{io};
+
+use crate::distrib::{self, Choice};
+use crate::game::{GameState, Rules};
+use crate::policy::{PlayerModel, Policy};
+
+/// The decisions of a single player collected from the finished games in which she played.
+/// The decisions are stored with the probabilities of their options under the base policy,
+/// so that we can fit a `PlayerModel` of the player without replaying the games (which may
+/// have been played under different rules). All games in a profile must be recorded under
+/// the same policy, because the models adjust the probabilities of this policy.
+#[derive(Debug, Clone, Default)]
+pub struct Profile {
+    /// The name of the policy with its parameters (see `Policy::name()`), or `None` if no
+    /// game has been recorded.
+    policy: Option<String>,
+    games: Vec<RecordedGame>,
+}
+
+#[derive(Debug, Clone)]
+struct RecordedGame {
+    /// The indices of the cards played by all players in all rounds, which identify the
+    /// game, so that we don't record the same game twice.
+    actions: Vec<usize>,
+    decisions: Vec<Decision>,
+}
+
+#[derive(Debug, Clone)]
+enum Decision {
+    /// The player played the first of the cards, which have the given probabilities and
+    /// positions (see `PlayerModel::card_position()`).
+    Action { probs: Vec<f32>, positions: Vec<f32> },
+    /// The player ate the row `row_i`.
+    Row { row_i: usize, probs: Vec<f32> },
+}
+
+impl Profile {
+    pub fn decision_count(&self) -> usize {
+        self.games.iter().map(|game| game.decisions.len()).sum()
+    }
+
+    /// Returns the policy under which the games were recorded.
+    pub fn policy(&self) -> Option<&str> {
+        self.policy.as_deref()
+    }
+
+    /// Returns true if the games were recorded under the given policy (or if there are no
+    /// games), so that we can add more games or fit models of the `policy` to the profile.
+    pub fn is_compatible(&self, policy: &dyn Policy) -> bool {
+        self.policy.as_ref().is_none_or(|name| *name == policy.name())
+    }
+
+    /// Records the decisions of player `player_i` in the finished game. Returns false if
+    /// the game was already recorded. The profile must be compatible with the `policy`.
+    pub fn add_game(
+        &mut self, rules: &Rules, policy: &dyn Policy, state: &GameState, player_i: usize,
+    ) -> bool
+    {
+        assert!(state.is_finished());
+        assert!(self.is_compatible(policy));
+        let actions = state.past_rounds.iter()
+            .flat_map(|round| round.actions.iter().map(|card| card.idx()))
+            .collect::<Vec<_>>();
+        if self.games.iter().any(|game| game.actions == actions) {
+            return false
+        }
+
+        // all cards have been played, so we know the full hand of the player
+        let hand = state.past_rounds.iter()
+            .map(|round| round.actions[player_i])
+            .collect::<Vec<_>>();
+        let mut decisions = Vec::new();
+        distrib::for_each_choice(rules, policy, state, state.past_rounds.len(), player_i, &hand,
+            |choice| match choice {
+                // the last card is not a decision
+                Choice::Action { hand, .. } if hand.len() == 1 => {},
+                Choice::Action { hand, probs } => decisions.push(Decision::Action {
+                    probs,
+                    positions: hand.iter()
+                        .map(|&card| PlayerModel::card_position(rules, card))
+                        .collect(),
+                }),
+                Choice::Row { row_i, probs } => decisions.push(Decision::Row { row_i, probs }),
+            });
+        self.policy = Some(policy.name());
+        self.games.push(RecordedGame { actions, decisions });
+        true
+    }
+
+    /// Calculates the log-likelihood of the recorded decisions under the base policy
+    /// adjusted by the `model`.
+    pub fn log_likelihood(&self, model: &PlayerModel) -> f32 {
+        self.games.iter().flat_map(|game| game.decisions.iter())
+            .map(|decision| match decision {
+                Decision::Action { probs, positions } => {
+                    let adjusted_probs = probs.iter().zip(positions.iter())
+                        .map(|(&prob, &position)| model.adjust_card_prob(prob, position))
+                        .collect::<Vec<_>>();
+                    (adjusted_probs[0] / adjusted_probs.iter().sum::<f32>()).ln()
+                },
+                Decision::Row { row_i, probs } => {
+                    let mut probs = probs.clone();
+                    model.adjust_row_probs(&mut probs);
+                    probs[*row_i].ln()
+                },
+            })
+            .sum()
+    }
+
+    /// Writes the profile in a text format: the first line "policy" is followed by the
+    /// name of the policy, every game starts with a line "game" followed by the played
+    /// cards, and every decision is on a separate line, either "a" followed by
+    /// "prob:position" of every card (the played card first), or "r" followed by the
+    /// chosen row (counted from 1) and the probabilities of all rows.
+    pub fn write<W: io::Write>(&self, mut output: W) -> io::Result<()> {
+        if let Some(ref policy) = self.policy {
+            writeln!(output, "policy {}", policy)?;
+        }
+        for game in self.games.iter() {
+            write!(output, "game")?;
+            for idx in game.actions.iter() {
+                write!(output, " {}", idx)?;
+            }
+            writeln!(output)?;
+
+            for decision in game.decisions.iter() {
+                match decision {
+                    Decision::Action { probs, positions } => {
+                        write!(output, "a")?;
+                        for (prob, position) in probs.iter().zip(positions.iter()) {
+                            write!(output, " {}:{}", prob, position)?;
+                        }
+                    },
+                    Decision::Row { row_i, probs } => {
+                        write!(output, "r {}", row_i + 1)?;
+                        for prob in probs.iter() {
+                            write!(output, " {}", prob)?;
+                        }
+                    },
+                }
+                writeln!(output)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a profile written by `write()`.
+    pub fn read<I: io::BufRead>(input: I) -> io::Result<Profile> {
+        let mut policy = None;
+        let mut games: Vec<RecordedGame> = Vec::new();
+        for (line_i, line) in input.lines().enumerate() {
+            let line = line?;
+            let err = |reason: &str| {
+                let msg = format!("{}: {}", line_i + 1, reason);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            };
+            let parse_prob = |word: &str| match word.parse::<f32>() {
+                Ok(prob) if (0. ..=1.).contains(&prob) => Ok(prob),
+                _ => Err(err("bad probability")),
+            };
+
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            if words.is_empty() {
+                continue
+            } else if words[0] == "policy" {
+                if policy.is_some() || words.len() < 2 { return Err(err("bad policy")); }
+                policy = Some(words[1..].join(" "));
+                continue
+            } else if words[0] == "game" {
+                if policy.is_none() { return Err(err("game before the policy")); }
+                let actions = words[1..].iter()
+                    .map(|word| word.parse::<usize>().map_err(|_| err("bad card")))
+                    .collect::<io::Result<Vec<_>>>()?;
+                games.push(RecordedGame { actions, decisions: Vec::new() });
+                continue
+            }
+
+            let game = games.last_mut().ok_or_else(|| err("decision outside of a game"))?;
+            let decision = if words[0] == "a" {
+                let (probs, positions) = words[1..].iter()
+                    .map(|word| {
+                        let (prob, position) = word.split_once(':')
+                            .ok_or_else(|| err("expected prob:position"))?;
+                        Ok((parse_prob(prob)?, parse_prob(position)?))
+                    })
+                    .collect::<io::Result<Vec<_>>>()?
+                    .into_iter().unzip::<_, _, Vec<f32>, Vec<f32>>();
+                if probs.len() < 2 { return Err(err("too few cards")); }
+                Decision::Action { probs, positions }
+            } else if words[0] == "r" {
+                let row = words.get(1).and_then(|word| word.parse::<usize>().ok())
+                    .ok_or_else(|| err("bad row"))?;
+                let probs = words[2..].iter().cloned().map(parse_prob)
+                    .collect::<io::Result<Vec<_>>>()?;
+                if row < 1 || row > probs.len() { return Err(err("bad row")); }
+                Decision::Row { row_i: row - 1, probs }
+            } else {
+                return Err(err("unknown decision"));
+            };
+            game.decisions.push(decision);
+        }
+        Ok(Profile { policy, games })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::game::{Variant};
+    use crate::policy::{Policy1, RandomPolicy};
+    use crate::test_utils::{GameBuilder, small_rules};
+    use super::*;
+
+    #[test]
+    fn test_profile() {
+        // bob eats the first row with his 6 in the last round
+        let rules = small_rules(Variant::Standard, 20, 5, 2, 4);
+        let state = GameBuilder::new(&rules, 3, &[&[5], &[10], &[15], &[18]])
+            .round(&[7, 16, 11])
+            .round_with_choices(&[12, 19, 6], &[None, None, Some(0)])
+            .state(&[]);
+
+        let mut profile = Profile::default();
+        assert!(profile.add_game(&rules, &Policy1::default(), &state, 2));
+        assert!(!profile.add_game(&rules, &Policy1::default(), &state, 2));
+        // the first action and the row choice
+        assert_eq!(profile.decision_count(), 2);
+
+        let mut output = Vec::new();
+        profile.write(&mut output).unwrap();
+        let read_profile = Profile::read(&output[..]).unwrap();
+        assert_eq!(read_profile.decision_count(), 2);
+        assert_eq!(read_profile.policy(), profile.policy());
+        assert!(read_profile.is_compatible(&Policy1::default()));
+        assert!(!read_profile.is_compatible(&RandomPolicy));
+        for &model in [PlayerModel::NEUTRAL, PlayerModel { temperature: 2., card_bias: -1. }].iter() {
+            assert_eq!(read_profile.log_likelihood(&model), profile.log_likelihood(&model));
+        }
+
+        // the decisions are meaningless without the policy
+        assert!(Profile::read(&b"game 1 2\na 0.5:0 0.5:1\n"[..]).is_err());
+    }
+}
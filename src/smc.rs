@@ -0,0 +1,448 @@
+use std::{cmp, io};
+use rand::{Rng, RngCore};
+
+use crate::{distrib, utils};
+use crate::distrib::{HandsDistrib};
+use crate::game::{Card, GameState, Rules};
+use crate::mcmc::{Chain};
+use crate::policy::{Policy};
+use crate::utils::{SearchBudget};
+
+/// The number of Metropolis-Hastings steps that rejuvenate every particle after
+/// conditioning on a round.
+const REJUVENATION_STEPS: usize = 10;
+
+/// A particle filter (sequential Monte Carlo) over the deals: every particle is a possible
+/// deal of the cards to the other players and the deck. The filter starts from uniformly
+/// sampled deals and conditions on the observed rounds one at a time, so that it can be
+/// saved and later updated with the new rounds only.
+#[derive(Debug, Clone)]
+pub struct ParticleFilter {
+    /// The number of observed rounds that the particles are conditioned on.
+    round_count: usize,
+    /// The name of the policy under which the particles are conditioned on the rounds (see
+    /// `Policy::name()`), or `None` if they are not conditioned on any round.
+    policy: Option<String>,
+    particles: Vec<Particle>,
+    /// The numbers of accepted and proposed rejuvenation moves.
+    accepted_count: usize,
+    proposed_count: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Particle {
+    /// The full hands of all owners (owner 0 is the deck), in the format of
+    /// `distrib::sample_hands_uniform()`: the first `round_count` cards of every player are
+    /// the cards that the player played in the observed rounds.
+    hands: Vec<Vec<Card>>,
+    log_weight: f32,
+}
+
+impl ParticleFilter {
+    /// Creates the filter with uniformly sampled deals, which is not conditioned on any
+    /// round. The number of particles is derived from the `budget`, so that the
+    /// rejuvenation of all particles after a round takes about `budget.iterations` steps.
+    pub fn new(rng: &mut dyn RngCore, rules: &Rules, state: &GameState, budget: SearchBudget)
+        -> ParticleFilter
+    {
+        let particle_count = cmp::max(budget.iterations / REJUVENATION_STEPS, 1);
+        let mut cards = deal_cards(rules, state);
+        let no_known_hands = vec![Vec::new(); state.player_count];
+        let particles = (0..particle_count)
+            .map(|_| Particle {
+                hands: distrib::sample_hands_uniform(rng, rules, &no_known_hands, &mut cards),
+                log_weight: 0.,
+            })
+            .collect();
+        ParticleFilter {
+            round_count: 0, policy: None, particles, accepted_count: 0, proposed_count: 0,
+        }
+    }
+
+    /// Conditions the filter on all observed rounds of the `state` that it is not yet
+    /// conditioned on.
+    pub fn update(
+        &mut self, rng: &mut dyn RngCore, rules: &Rules, policy: &dyn Policy,
+        state: &GameState, thread_count: usize,
+    ) {
+        let round_count = state.observed_rounds().count();
+        assert!(self.round_count <= round_count);
+        assert!(self.is_compatible(policy));
+        for round_i in self.round_count..round_count {
+            self.condition(rng, rules, policy, state, round_i, thread_count);
+        }
+        if self.round_count > 0 {
+            self.policy = Some(policy.name());
+        }
+    }
+
+    /// Returns the policy under which the particles are conditioned on the rounds.
+    pub fn policy(&self) -> Option<&str> {
+        self.policy.as_deref()
+    }
+
+    /// Returns true if the particles are conditioned on the rounds under the given policy
+    /// (or on no rounds at all), so that we can condition them on more rounds.
+    pub fn is_compatible(&self, policy: &dyn Policy) -> bool {
+        self.policy.as_ref().is_none_or(|name| *name == policy.name())
+    }
+
+    /// Conditions the filter on the observed round `round_i` (see `reveal_round()`). When
+    /// the weights become too uneven, the particles are resampled, and then they are
+    /// rejuvenated by a few steps of the Markov chain.
+    fn condition(
+        &mut self, rng: &mut dyn RngCore, rules: &Rules, policy: &dyn Policy,
+        state: &GameState, round_i: usize, thread_count: usize,
+    ) {
+        self.reveal_round(rng, rules, policy, state, round_i, thread_count);
+        if self.effective_sample_size() < 0.5 * self.particles.len() as f32 {
+            self.resample(rng);
+        }
+        self.rejuvenate(rng, rules, policy, state, thread_count);
+    }
+
+    /// Moves the cards that the players played in the observed round `round_i` into their
+    /// hands in every particle (see `reveal_card()`) and updates the weights, so that the
+    /// particles are weighted by the posterior given the first `round_i + 1` rounds.
+    ///
+    /// The moves are a proposal `K(h -> h')` from the old deal `h` to the new deal `h'`, so
+    /// the incremental weight of a sequential Monte Carlo sampler is
+    /// `Pr(h') L(h' -> h) / (Pr(h) K(h -> h'))`, where `Pr` is the posterior given the
+    /// first `round_i + 1` rounds (for `h'`) or `round_i` rounds (for `h`) and `L` is any
+    /// backward kernel. For a single card, we choose `L` as the prior probability of the
+    /// reverse move: with probability `m / (m + n)`, the card was already in the hand (so
+    /// `h = h'` and `K = 1`), otherwise it was swapped with one of the `n` unplayed cards of
+    /// other owners (so `K = 1 / m`, where `m` is the number of unplayed cards in the hand).
+    /// In both cases `L / K = m / (m + n)`, which is the same for all particles, so the
+    /// incremental weight is just `Pr(h') / Pr(h)` and the weights stay unbiased.
+    fn reveal_round(
+        &mut self, rng: &mut dyn RngCore, rules: &Rules, policy: &dyn Policy,
+        state: &GameState, round_i: usize, thread_count: usize,
+    ) {
+        let round = state.observed_rounds().nth(round_i).unwrap();
+        let particles = &self.particles;
+        let thread_particles = utils::run_parallel(rng, thread_count, particles.len(),
+            |rng, particle_is| particles[particle_is].iter().map(|particle| {
+                let old_log_prob = calc_log_prob(rules, policy, state, round_i, &particle.hands);
+                let mut hands = particle.hands.clone();
+                for player_i in 1..state.player_count {
+                    reveal_card(rng, &mut hands, round_i, player_i, round.actions[player_i]);
+                }
+                let new_log_prob = calc_log_prob(rules, policy, state, round_i + 1, &hands);
+                Particle { hands, log_weight: particle.log_weight + new_log_prob - old_log_prob }
+            }).collect::<Vec<_>>());
+        self.particles = thread_particles.concat();
+        self.round_count = round_i + 1;
+    }
+
+    fn effective_sample_size(&self) -> f32 {
+        let max_log_weight = self.particles.iter()
+            .fold(-f32::INFINITY, |x, particle| f32::max(x, particle.log_weight));
+        let weights = self.particles.iter()
+            .map(|particle| (particle.log_weight - max_log_weight).exp())
+            .collect::<Vec<_>>();
+        let weight_sum = weights.iter().sum::<f32>();
+        let weight_sq_sum = weights.iter().map(|weight| weight * weight).sum::<f32>();
+        weight_sum * weight_sum / weight_sq_sum
+    }
+
+    /// Replaces the particles by a systematic resample according to their weights.
+    fn resample(&mut self, rng: &mut dyn RngCore) {
+        let max_log_weight = self.particles.iter()
+            .fold(-f32::INFINITY, |x, particle| f32::max(x, particle.log_weight));
+        let mut weights = self.particles.iter()
+            .map(|particle| (particle.log_weight - max_log_weight).exp())
+            .collect::<Vec<_>>();
+        utils::normalize_pdf(&mut weights);
+        self.particles = utils::resample_systematic(rng, &weights, self.particles.len())
+            .into_iter()
+            .map(|particle_i| {
+                let hands = self.particles[particle_i].hands.clone();
+                Particle { hands, log_weight: 0. }
+            })
+            .collect();
+    }
+
+    /// Moves every particle by a few steps of the Markov chain from `mcmc`, which samples
+    /// from the posterior given the rounds that the filter is conditioned on.
+    fn rejuvenate(
+        &mut self, rng: &mut dyn RngCore, rules: &Rules, policy: &dyn Policy,
+        state: &GameState, thread_count: usize,
+    ) {
+        let round_count = self.round_count;
+        let particles = &self.particles;
+        let thread_results = utils::run_parallel(rng, thread_count, particles.len(),
+            |rng, particle_is| {
+                let mut accepted_count = 0;
+                let mut proposed_count = 0;
+                let particles = particles[particle_is].iter().map(|particle| {
+                    let hands = particle.hands.clone();
+                    let mut chain = Chain::new(rules, policy, state, round_count, hands);
+                    for _ in 0..REJUVENATION_STEPS {
+                        chain.step(rng, rules, policy, state);
+                    }
+                    accepted_count += chain.accepted_count;
+                    proposed_count += chain.proposed_count;
+                    Particle { hands: chain.hands, log_weight: particle.log_weight }
+                }).collect::<Vec<_>>();
+                (particles, accepted_count, proposed_count)
+            });
+
+        self.particles.clear();
+        for (particles, accepted_count, proposed_count) in thread_results.into_iter() {
+            self.particles.extend(particles);
+            self.accepted_count += accepted_count;
+            self.proposed_count += proposed_count;
+        }
+    }
+
+    /// Converts the particles into a distribution of the hands in the current state. The
+    /// filter must be conditioned on all observed rounds.
+    pub fn to_distrib(&self, rules: &Rules, state: &GameState) -> HandsDistrib {
+        assert_eq!(self.round_count, state.observed_rounds().count());
+        let samples = self.particles.iter()
+            .map(|particle| particle.hands.clone())
+            .collect::<Vec<_>>();
+        let log_weights = self.particles.iter()
+            .map(|particle| particle.log_weight)
+            .collect::<Vec<_>>();
+        let acceptance_rate =
+            if self.proposed_count == 0 { None }
+            else { Some(self.accepted_count as f32 / self.proposed_count as f32) };
+        HandsDistrib::from_samples(rules, state, samples, &log_weights, acceptance_rate)
+    }
+
+    /// Writes the particles in a text format: the name of the policy (if the filter is
+    /// conditioned on some rounds) and the number of rounds that the filter is conditioned
+    /// on, followed by a line for every particle with its log-weight and the hands of all
+    /// owners separated by '|'.
+    pub fn write<W: io::Write>(&self, mut output: W) -> io::Result<()> {
+        if let Some(ref policy) = self.policy {
+            writeln!(output, "policy {}", policy)?;
+        }
+        writeln!(output, "rounds {}", self.round_count)?;
+        for particle in self.particles.iter() {
+            write!(output, "{}", particle.log_weight)?;
+            for hand in particle.hands.iter() {
+                write!(output, " |")?;
+                for card in hand.iter() {
+                    write!(output, " {}", card.idx())?;
+                }
+            }
+            writeln!(output)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the particles written by `write()` and checks that they are consistent with
+    /// the `state`: they must deal the same cards and they must be conditioned on the
+    /// observed rounds of the `state`.
+    pub fn read<I: io::BufRead>(input: I, rules: &Rules, state: &GameState)
+        -> io::Result<ParticleFilter>
+    {
+        let mut sorted_deal_cards = deal_cards(rules, state);
+        sorted_deal_cards.sort_unstable_by_key(|card| card.idx());
+        let observed_rounds = state.observed_rounds().collect::<Vec<_>>();
+
+        let mut policy = None;
+        let mut round_count = None;
+        let mut particles = Vec::new();
+        for (line_i, line) in input.lines().enumerate() {
+            let line = line?;
+            let err = |reason: &str| {
+                let msg = format!("{}: {}", line_i + 1, reason);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            };
+
+            let round_count = match round_count {
+                Some(round_count) => round_count,
+                None => {
+                    if let Some(name) = line.strip_prefix("policy ") {
+                        if policy.is_some() { return Err(err("bad policy")); }
+                        policy = Some(name.trim().to_string());
+                        continue
+                    }
+                    let value = line.strip_prefix("rounds ")
+                        .and_then(|value| value.parse::<usize>().ok())
+                        .ok_or_else(|| err("expected the number of rounds"))?;
+                    if value > observed_rounds.len() {
+                        return Err(err("the particles are conditioned on more rounds than \
+                            were observed in the game"));
+                    }
+                    if value > 0 && policy.is_none() {
+                        return Err(err("the policy of the particles is missing"));
+                    }
+                    round_count = Some(value);
+                    continue
+                },
+            };
+
+            let mut parts = line.split('|');
+            let log_weight = parts.next().unwrap().trim().parse::<f32>()
+                .map_err(|_| err("could not parse the weight"))?;
+            let hands = parts
+                .map(|part| part.split_whitespace().map(|word| {
+                    match word.parse::<usize>() {
+                        Ok(idx) if idx >= rules.min_card_idx && idx <= rules.max_card_idx =>
+                            Ok(rules.card(idx)),
+                        _ => Err(err("bad card")),
+                    }
+                }).collect::<io::Result<Vec<Card>>>())
+                .collect::<io::Result<Vec<Vec<Card>>>>()?;
+
+            if hands.len() != state.player_count {
+                return Err(err("the number of hands does not match the number of players"));
+            }
+            if hands[1..].iter().any(|hand| hand.len() != rules.hand_len) {
+                return Err(err("the hand of a player has a wrong number of cards"));
+            }
+            for (round_i, round) in observed_rounds[..round_count].iter().enumerate() {
+                if (1..state.player_count).any(|player_i| hands[player_i][round_i] != round.actions[player_i]) {
+                    return Err(err("the particle does not match the cards played in the game"));
+                }
+            }
+            let mut cards = hands.iter().flat_map(|hand| hand.iter().cloned()).collect::<Vec<_>>();
+            cards.sort_unstable_by_key(|card| card.idx());
+            if cards != sorted_deal_cards {
+                return Err(err("the particle does not deal the cards of the game"));
+            }
+
+            particles.push(Particle { hands, log_weight });
+        }
+
+        let round_count = round_count.unwrap_or(0);
+        if particles.is_empty() {
+            let msg = "the file contains no particles";
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+        }
+        Ok(ParticleFilter { round_count, policy, particles, accepted_count: 0, proposed_count: 0 })
+    }
+}
+
+/// Returns the cards that the particles deal to the other players and the deck: the cards
+/// that the other players played in the observed rounds and the unknown cards. The game may
+/// be recorded from the middle of a deal, so these are not necessarily all cards that were
+/// dealt, but they do not change when new rounds are added to the game.
+fn deal_cards(rules: &Rules, state: &GameState) -> Vec<Card> {
+    let (known_hands, unknown_cards) = distrib::split_cards(rules, state);
+    known_hands.into_iter().flatten().chain(unknown_cards).collect()
+}
+
+/// Calculates the log-likelihood of the first `round_count` observed rounds given the full
+/// hands (see `distrib::calc_hands_log_prob()`).
+fn calc_log_prob(
+    rules: &Rules, policy: &dyn Policy, state: &GameState,
+    round_count: usize, hands: &[Vec<Card>],
+) -> f32
+{
+    (1..hands.len())
+        .map(|player_i| distrib::calc_player_log_prob(rules, policy, state, round_count,
+            player_i, &hands[player_i]))
+        .sum()
+}
+
+/// Moves the `card`, which the player played in the round `round_i`, to the position
+/// `round_i` in the hand of the player. If the card is not in the hand, it is swapped with
+/// a random card that the player has not played yet.
+fn reveal_card(
+    rng: &mut dyn RngCore, hands: &mut [Vec<Card>],
+    round_i: usize, player_i: usize, card: Card,
+) {
+    let card_i = match hands[player_i].iter().position(|&c| c == card) {
+        Some(card_i) => card_i,
+        None => {
+            let (owner_i, owner_card_i) = hands.iter().enumerate()
+                .find_map(|(owner_i, hand)| hand.iter().position(|&c| c == card)
+                    .map(|owner_card_i| (owner_i, owner_card_i)))
+                .unwrap();
+            let card_i = rng.gen_range(round_i, hands[player_i].len());
+            hands[owner_i][owner_card_i] = hands[player_i][card_i];
+            hands[player_i][card_i] = card;
+            card_i
+        },
+    };
+    hands[player_i].swap(round_i, card_i);
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap};
+    use rand_pcg::{Pcg64Mcg};
+    use crate::game::{Variant};
+    use crate::policy::{Policy1, PolicyTransform, RandomPolicy};
+    use crate::test_utils::{GameBuilder, deal_distance, exact_deal_probs, make_cards, small_rules};
+    use super::*;
+
+    #[test]
+    fn test_particle_filter() {
+        // the deck has 2 * 3 + 2 = 8 cards, I had 2 6 8 and the opponent had 3 4 7
+        let rules = small_rules(Variant::Tactical, 8, 5, 3, 2);
+        let game = GameBuilder::new(&rules, 2, &[&[1], &[5]]).round(&[2, 3]);
+        let state = game.state(&[6, 8]);
+
+        let mut rng = Pcg64Mcg::new(42);
+        let budget = SearchBudget { iterations: 500, time: None };
+        let policy = Policy1::default();
+        let mut filter = ParticleFilter::new(&mut rng, &rules, &state, budget);
+        filter.update(&mut rng, &rules, &policy, &state, 2);
+
+        // save the filter and resume it in the next round, but only under the same policy
+        let mut output = Vec::new();
+        filter.write(&mut output).unwrap();
+        let state = game.round(&[6, 4]).state(&[8]);
+        let mut filter = ParticleFilter::read(&output[..], &rules, &state).unwrap();
+        assert_eq!(filter.round_count, 1);
+        assert!(filter.is_compatible(&policy));
+        assert!(!filter.is_compatible(&RandomPolicy));
+        assert!(!filter.is_compatible(&Policy1::new(PolicyTransform {
+            random_prob: 0.1, ..PolicyTransform::DEFAULT
+        })));
+        filter.update(&mut rng, &rules, &policy, &state, 2);
+        assert_eq!(filter.round_count, 2);
+
+        let distrib = filter.to_distrib(&rules, &state);
+        for _ in 0..100 {
+            let (hands, _) = distrib.sample(&mut rng, &rules);
+            assert_eq!(hands, vec![Vec::new(), make_cards(&rules, &[7])]);
+        }
+    }
+
+    fn particle_distance(
+        filter: &ParticleFilter, exact_probs: &HashMap<Vec<Vec<Card>>, f32>,
+    ) -> f32
+    {
+        let max_log_weight = filter.particles.iter()
+            .fold(-f32::INFINITY, |x, particle| f32::max(x, particle.log_weight));
+        deal_distance(exact_probs, filter.particles.iter()
+            .map(|particle| (particle.hands.clone(), (particle.log_weight - max_log_weight).exp())))
+    }
+
+    #[test]
+    fn test_posterior() {
+        // after two rounds, the 6 unknown cards 1 5 6 8 13 16 are split between the hands
+        // of the opponents and the deck in one of 90 ways, which are far from equally likely
+        let rules = small_rules(Variant::Standard, 16, 3, 4, 2);
+        let state = GameBuilder::new(&rules, 3, &[&[3], &[9]])
+            .round(&[4, 10, 12])
+            .round(&[14, 11, 2])
+            .state(&[7, 15]);
+        let policy = Policy1::default();
+        let exact_probs = exact_deal_probs(&rules, &policy, &state);
+        assert_eq!(exact_probs.len(), 90);
+        let mut rng = Pcg64Mcg::new(42);
+
+        // the weights alone (without resampling and rejuvenation) must be unbiased
+        let budget = SearchBudget { iterations: 500000, time: None };
+        let mut filter = ParticleFilter::new(&mut rng, &rules, &state, budget);
+        filter.reveal_round(&mut rng, &rules, &policy, &state, 0, 2);
+        filter.reveal_round(&mut rng, &rules, &policy, &state, 1, 2);
+        let distance = particle_distance(&filter, &exact_probs);
+        assert!(distance < 0.03, "{}", distance);
+
+        let budget = SearchBudget { iterations: 200000, time: None };
+        let mut filter = ParticleFilter::new(&mut rng, &rules, &state, budget);
+        filter.update(&mut rng, &rules, &policy, &state, 2);
+        let distance = particle_distance(&filter, &exact_probs);
+        assert!(distance < 0.03, "{}", distance);
+    }
+}
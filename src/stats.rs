@@ -0,0 +1,286 @@
+use crate::utils;
+
+/// Accumulates weighted samples of the values of several actions, where every sample
+/// gives a value to all actions and has a single (importance) weight. The values of the
+/// actions in a sample may be correlated (for example, when they share the random numbers),
+/// so we also track the products of pairs of values to estimate the variance of the
+/// differences. The sums are kept in f64 to avoid cancellation when computing the variance.
+#[derive(Debug, Clone)]
+pub struct WeightedStats {
+    sample_count: usize,
+    weight_sum: f64,
+    weight_sq_sum: f64,
+    /// Sums of `weight * value` for every action.
+    value_sums: Vec<f64>,
+    /// Sums of `weight^2 * value` for every action.
+    weight_value_sums: Vec<f64>,
+    /// Sums of `weight^2 * value_i * value_j` for every pair of actions.
+    value_product_sums: Vec<f64>,
+}
+
+impl WeightedStats {
+    pub fn new(action_count: usize) -> WeightedStats {
+        WeightedStats {
+            sample_count: 0,
+            weight_sum: 0.,
+            weight_sq_sum: 0.,
+            value_sums: vec![0.; action_count],
+            weight_value_sums: vec![0.; action_count],
+            value_product_sums: vec![0.; action_count * action_count],
+        }
+    }
+
+    pub fn add(&mut self, weight: f32, values: &[f32]) {
+        let weight = weight as f64;
+        self.sample_count += 1;
+        self.weight_sum += weight;
+        self.weight_sq_sum += weight * weight;
+        let action_count = values.len();
+        for (i, &value) in values.iter().enumerate() {
+            let value = value as f64;
+            self.value_sums[i] += weight * value;
+            self.weight_value_sums[i] += weight * weight * value;
+            for (j, &value_j) in values.iter().enumerate() {
+                self.value_product_sums[i * action_count + j] +=
+                    (weight * value) * (weight * value_j as f64);
+            }
+        }
+    }
+
+    /// Adds all samples from `other` to this accumulator.
+    pub fn merge(&mut self, other: &WeightedStats) {
+        self.sample_count += other.sample_count;
+        self.weight_sum += other.weight_sum;
+        self.weight_sq_sum += other.weight_sq_sum;
+        for i in 0..self.value_sums.len() {
+            self.value_sums[i] += other.value_sums[i];
+            self.weight_value_sums[i] += other.weight_value_sums[i];
+        }
+        for i in 0..self.value_product_sums.len() {
+            self.value_product_sums[i] += other.value_product_sums[i];
+        }
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.sample_count
+    }
+
+    /// Returns the effective sample size (sum w)^2 / sum w^2, which is lower than the
+    /// number of samples when the weights are uneven.
+    pub fn effective_sample_size(&self) -> f32 {
+        (self.weight_sum * self.weight_sum / self.weight_sq_sum) as f32
+    }
+
+    /// Returns the weighted mean value of the action.
+    pub fn mean(&self, action_i: usize) -> f32 {
+        (self.value_sums[action_i] / self.weight_sum) as f32
+    }
+
+    /// Returns the standard error of the weighted mean of the action, which is estimated
+    /// as sqrt(sum w^2 (x - mean)^2) / sum w.
+    pub fn std_error(&self, action_i: usize) -> f32 {
+        self.combined_std_error(&[(action_i, 1.)])
+    }
+
+    /// Returns the standard error of the difference between the weighted means of the
+    /// actions. Because the values in a sample may be correlated, this may be much lower
+    /// than the standard errors of the means themselves.
+    pub fn diff_std_error(&self, action_i: usize, action_j: usize) -> f32 {
+        if action_i == action_j { return 0. }
+        self.combined_std_error(&[(action_i, 1.), (action_j, -1.)])
+    }
+
+    /// Returns the standard error of the weighted mean of the linear combination
+    /// `sum coef * value` of the values of the actions.
+    fn combined_std_error(&self, coefs: &[(usize, f64)]) -> f32 {
+        let action_count = self.value_sums.len();
+        let mean = coefs.iter()
+            .map(|&(i, coef)| coef * self.value_sums[i])
+            .sum::<f64>() / self.weight_sum;
+        let weight_value_sum = coefs.iter()
+            .map(|&(i, coef)| coef * self.weight_value_sums[i])
+            .sum::<f64>();
+        let value_sq_sum = coefs.iter()
+            .flat_map(|&(i, coef_i)| coefs.iter().map(move |&(j, coef_j)| (i, coef_i, j, coef_j)))
+            .map(|(i, coef_i, j, coef_j)| coef_i * coef_j * self.value_product_sums[i * action_count + j])
+            .sum::<f64>();
+        let sq_dev_sum = value_sq_sum
+            - 2. * mean * weight_value_sum
+            + mean * mean * self.weight_sq_sum;
+        (f64::max(sq_dev_sum, 0.).sqrt() / self.weight_sum) as f32
+    }
+
+    pub fn estimates(&self) -> Estimates {
+        let action_count = self.value_sums.len();
+        let means = (0..action_count).map(|i| self.mean(i)).collect::<Vec<_>>();
+        let best_i = best_i(&means);
+        Estimates {
+            means,
+            std_errors: (0..action_count).map(|i| self.std_error(i)).collect(),
+            diff_std_errors: (0..action_count).map(|i| self.diff_std_error(i, best_i)).collect(),
+            sample_count: self.sample_count,
+            effective_sample_size: self.effective_sample_size(),
+        }
+    }
+}
+
+/// The estimated values of actions (lower is better) with their standard errors.
+#[derive(Debug, Clone)]
+pub struct Estimates {
+    pub means: Vec<f32>,
+    pub std_errors: Vec<f32>,
+    /// The standard errors of the differences between the means of the actions and the
+    /// mean of the best action (the one with the lowest mean).
+    pub diff_std_errors: Vec<f32>,
+    pub sample_count: usize,
+    pub effective_sample_size: f32,
+}
+
+impl Estimates {
+    /// Returns the index of the action with the lowest mean.
+    pub fn best_i(&self) -> usize {
+        best_i(&self.means)
+    }
+
+    /// Returns true if the mean of action `i` is higher than the mean of the best action by
+    /// more than `margin` standard errors of their difference. An action that ties with the
+    /// best action is never worse, even if the standard error is zero.
+    pub fn is_worse_than_best(&self, i: usize, margin: f32) -> bool {
+        let best_i = self.best_i();
+        i != best_i && self.means[i] - self.means[best_i] > margin * self.diff_std_errors[i]
+    }
+
+    /// Returns true if the best action is better than all other actions by at least
+    /// `margin` standard errors. It is not enough to compare the best action with the
+    /// runner-up: the differences are estimated from paired samples, so a worse action
+    /// whose values are less correlated with the best action may be harder to separate
+    /// from it than the runner-up.
+    pub fn is_best_separated(&self, margin: f32) -> bool {
+        let best_i = self.best_i();
+        (0..self.means.len()).all(|i| i == best_i || self.is_worse_than_best(i, margin))
+    }
+}
+
+/// Accumulates importance weights given by their logarithms (which may be very small), to
+/// find out whether a few samples dominate all others.
+#[derive(Debug, Clone)]
+pub struct LogWeightStats {
+    sample_count: usize,
+    log_weight_sum: f32,
+    log_weight_sq_sum: f32,
+    max_log_weight: f32,
+}
+
+impl LogWeightStats {
+    pub fn new() -> LogWeightStats {
+        LogWeightStats {
+            sample_count: 0,
+            log_weight_sum: -f32::INFINITY,
+            log_weight_sq_sum: -f32::INFINITY,
+            max_log_weight: -f32::INFINITY,
+        }
+    }
+
+    pub fn add(&mut self, log_weight: f32) {
+        self.sample_count += 1;
+        self.log_weight_sum = utils::log_add(self.log_weight_sum, log_weight);
+        self.log_weight_sq_sum = utils::log_add(self.log_weight_sq_sum, 2. * log_weight);
+        self.max_log_weight = f32::max(self.max_log_weight, log_weight);
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.sample_count
+    }
+
+    /// Returns the effective sample size (sum w)^2 / sum w^2.
+    pub fn effective_sample_size(&self) -> f32 {
+        (2. * self.log_weight_sum - self.log_weight_sq_sum).exp()
+    }
+
+    /// Returns the fraction of the total weight that falls on the heaviest sample.
+    pub fn max_weight_fraction(&self) -> f32 {
+        (self.max_log_weight - self.log_weight_sum).exp()
+    }
+}
+
+/// Returns the index of the lowest mean.
+pub fn best_i(means: &[f32]) -> usize {
+    (0..means.len())
+        .min_by(|&i, &j| utils::compare_f32(means[i], means[j]))
+        .unwrap()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_weighted_stats() {
+        let mut stats_1 = WeightedStats::new(2);
+        stats_1.add(1., &[1., 10.]);
+        stats_1.add(2., &[4., 10.]);
+        let mut stats_2 = WeightedStats::new(2);
+        stats_2.add(1., &[7., 10.]);
+        stats_1.merge(&stats_2);
+
+        let estimates = stats_1.estimates();
+        assert_eq!(estimates.sample_count, 3);
+        assert_eq!(estimates.effective_sample_size, 16. / 6.);
+        assert_eq!(estimates.means, vec![4., 10.]);
+        // sqrt(1*9 + 4*0 + 1*9) / 4
+        assert_eq!(estimates.std_errors, vec![18f32.sqrt() / 4., 0.]);
+        assert_eq!(estimates.diff_std_errors, vec![0., 18f32.sqrt() / 4.]);
+        assert_eq!(estimates.best_i(), 0);
+        assert!(estimates.is_best_separated(5.));
+        assert!(!estimates.is_best_separated(6.));
+    }
+
+    #[test]
+    fn test_paired_diff() {
+        // the values of the actions differ by a constant, so their difference is exact
+        let mut stats = WeightedStats::new(2);
+        stats.add(1., &[1., 2.]);
+        stats.add(2., &[5., 6.]);
+        stats.add(1., &[3., 4.]);
+        assert!(stats.std_error(0) > 0.5);
+        assert!(stats.diff_std_error(0, 1).abs() < 1e-6);
+        assert!(stats.estimates().is_best_separated(100.));
+    }
+
+    #[test]
+    fn test_best_separated_from_all() {
+        // the runner-up is worse than the best action by exactly 1 in every sample, but the
+        // difference of the third action varies a lot
+        let mut stats = WeightedStats::new(3);
+        stats.add(1., &[0., 1., 2.]);
+        stats.add(1., &[2., 3., 12.]);
+        stats.add(1., &[4., 5., 1.]);
+        let estimates = stats.estimates();
+        assert_eq!(estimates.means, vec![2., 3., 5.]);
+        assert!(estimates.is_worse_than_best(1, 3.));
+        assert!(!estimates.is_worse_than_best(2, 3.));
+        assert!(!estimates.is_best_separated(3.));
+
+        // the second action ties with the best action in every sample
+        let mut stats = WeightedStats::new(3);
+        stats.add(1., &[0., 0., 2.]);
+        stats.add(1., &[2., 2., 4.]);
+        let estimates = stats.estimates();
+        assert_eq!(estimates.diff_std_errors[1], 0.);
+        assert!(!estimates.is_worse_than_best(1, 3.));
+        assert!(estimates.is_worse_than_best(2, 3.));
+        assert!(!estimates.is_best_separated(3.));
+    }
+
+    #[test]
+    fn test_log_weight_stats() {
+        let mut stats = LogWeightStats::new();
+        stats.add(1f32.ln());
+        stats.add(2f32.ln());
+        stats.add(1f32.ln());
+
+        assert_eq!(stats.sample_count(), 3);
+        assert!((stats.effective_sample_size() - 16. / 6.).abs() < 1e-5);
+        assert!((stats.max_weight_fraction() - 0.5).abs() < 1e-5);
+    }
+}
@@ -0,0 +1,114 @@
+use std::collections::{HashMap};
+
+use crate::distrib;
+use crate::game::{Card, GameEngine, GameState, Round, Rules, Table, Variant};
+use crate::policy::{Policy};
+
+/// The rules with a small deck of cards from 1 to `max_card_idx` and the given size of the
+/// hands and of the table. Other rules are standard.
+pub fn small_rules(
+    variant: Variant, max_card_idx: usize, max_row_len: usize,
+    hand_len: usize, row_count: usize,
+) -> Rules
+{
+    Rules { max_card_idx, max_row_len, hand_len, row_count, variant, ..Rules::standard() }
+}
+
+pub fn make_table(rules: &Rules, rows: &[&[usize]]) -> Table {
+    Table::new(rows.iter().map(|row| make_cards(rules, row)).collect())
+}
+
+pub fn make_cards(rules: &Rules, idxs: &[usize]) -> Vec<Card> {
+    idxs.iter().map(|&idx| rules.card(idx)).collect()
+}
+
+/// Builds a `GameState` by playing rounds from the initial table.
+#[derive(Debug, Clone)]
+pub struct GameBuilder<'r> {
+    rules: &'r Rules,
+    engine: GameEngine<'r>,
+    player_count: usize,
+    past_rounds: Vec<Round>,
+}
+
+impl<'r> GameBuilder<'r> {
+    pub fn new(rules: &'r Rules, player_count: usize, rows: &[&[usize]]) -> GameBuilder<'r> {
+        let engine = GameEngine::new(rules, make_table(rules, rows));
+        GameBuilder { rules, engine, player_count, past_rounds: Vec::new() }
+    }
+
+    /// Plays a round with the cards of all players (me first). A card that is lower than
+    /// all rows eats the first row and the choice is not recorded.
+    pub fn round(self, actions: &[usize]) -> GameBuilder<'r> {
+        let row_choices = vec![None; actions.len()];
+        self.round_with_choices(actions, &row_choices)
+    }
+
+    /// Plays a round in which the players with a card lower than all rows eat the recorded
+    /// rows (or the first row if the choice is not recorded).
+    pub fn round_with_choices(
+        mut self, actions: &[usize], row_choices: &[Option<usize>],
+    ) -> GameBuilder<'r>
+    {
+        let table = self.engine.table().clone();
+        let actions = make_cards(self.rules, actions);
+        self.engine.resolve_round(&actions, |_, player_i, _| row_choices[player_i].unwrap_or(0));
+        self.past_rounds.push(Round { table, actions, row_choices: row_choices.to_vec() });
+        self
+    }
+
+    /// Returns the state after the rounds played so far, when I have the given cards.
+    pub fn state(&self, my_hand: &[usize]) -> GameState {
+        GameState {
+            my_hand: make_cards(self.rules, my_hand),
+            past_rounds: self.past_rounds.clone(),
+            table: self.engine.table().clone(),
+            player_count: self.player_count,
+            pending_round: None,
+            scores: None,
+        }
+    }
+}
+
+/// Computes the exact posterior probabilities of all deals, assuming that other players
+/// follow the `policy`. The deals are full hands of all owners (in the format of
+/// `distrib::sample_hands_uniform()`) with sorted cards.
+pub fn exact_deal_probs(
+    rules: &Rules, policy: &dyn Policy, state: &GameState,
+) -> HashMap<Vec<Vec<Card>>, f32>
+{
+    let (known_hands, unknown_cards) = distrib::split_cards(rules, state);
+    let mut probs = distrib::enumerate_deals(rules, &known_hands, &unknown_cards).into_iter()
+        .map(|hands| {
+            let prob = distrib::calc_hands_log_prob(rules, policy, state, &hands).exp();
+            (sorted_hands(hands), prob)
+        })
+        .collect::<HashMap<_, _>>();
+    let prob_sum = probs.values().sum::<f32>();
+    probs.values_mut().for_each(|prob| *prob /= prob_sum);
+    probs
+}
+
+/// Returns the total variation distance between the exact posterior (see
+/// `exact_deal_probs()`) and the weighted samples of full hands.
+pub fn deal_distance<I>(exact_probs: &HashMap<Vec<Vec<Card>>, f32>, samples: I) -> f32
+    where I: IntoIterator<Item = (Vec<Vec<Card>>, f32)>
+{
+    let mut probs = HashMap::new();
+    let mut weight_sum = 0.;
+    for (hands, weight) in samples {
+        *probs.entry(sorted_hands(hands)).or_insert(0.) += weight;
+        weight_sum += weight;
+    }
+    exact_probs.iter()
+        .map(|(hands, &exact_prob)| {
+            let prob = probs.get(hands).map_or(0., |prob| prob / weight_sum);
+            0.5 * (prob - exact_prob).abs()
+        })
+        .sum()
+}
+
+fn sorted_hands(mut hands: Vec<Vec<Card>>) -> Vec<Vec<Card>> {
+    hands.iter_mut().for_each(|hand| hand.sort_unstable_by_key(|card| card.idx()));
+    hands
+}
@@ -1,5 +1,6 @@
 use rand::{RngCore, Rng};
-use std::cmp::{Ordering};
+use rand_pcg::{Pcg64};
+use std::{cmp::{self, Ordering}, ops::{Range}, thread, time::{Duration, Instant}};
 
 pub fn normalize_pdf(pdf: &mut [f32]) {
     let inv_sum = 1. / pdf.iter().sum::<f32>();
@@ -17,22 +18,37 @@ pub fn normalize_log_pdf(log_pdf: &mut [f32]) {
 }
 */
 
-pub fn exp_normalize_log_pdf(log_pdf: &mut [f32]) {
-    let max = log_pdf.iter().fold(-f32::INFINITY, |x, &y| f32::max(x, y));
-    let sum_exp = log_pdf.iter().map(|x| (x - max).exp()).sum::<f32>();
-    let normalizer = max + sum_exp.ln();
-    log_pdf.iter_mut().for_each(|x| *x = (*x - normalizer).exp());
-}
-
 
 pub fn sample_pdf(rng: &mut dyn RngCore, pdf: &[f32]) -> usize {
-    let sample = rng.gen::<f32>();
+    sample_pdf_at(rng.gen::<f32>(), pdf)
+}
+
+/// Samples from the pdf using the given uniform random number from [0, 1) (by inverting
+/// the cumulative distribution function), so that the same number gives the same sample.
+pub fn sample_pdf_at(sample: f32, pdf: &[f32]) -> usize {
     let mut partial_sum = 0.;
     for (i, prob) in pdf.iter().enumerate() {
         partial_sum += prob;
         if partial_sum >= sample { return i }
     }
-    return 0;
+    0
+}
+
+/// Draws `sample_count` indices from the `pdf` with systematic resampling: the samples are
+/// evenly spaced with a single random offset, so every index i is drawn either
+/// floor(sample_count * pdf[i]) or ceil(sample_count * pdf[i]) times.
+pub fn resample_systematic(rng: &mut dyn RngCore, pdf: &[f32], sample_count: usize) -> Vec<usize> {
+    let offset = rng.gen::<f32>();
+    let mut partial_sum = 0.;
+    let mut i = 0;
+    (0..sample_count).map(|sample_i| {
+        let target = (sample_i as f32 + offset) / sample_count as f32;
+        while i + 1 < pdf.len() && partial_sum + pdf[i] < target {
+            partial_sum += pdf[i];
+            i += 1;
+        }
+        i
+    }).collect()
 }
 
 /// Computes the value of binomial distribution: what is the probability that exactly k
@@ -54,6 +70,9 @@ pub fn log_add(log_a: f32, log_b: f32) -> f32 {
     let (log_x, log_y) = 
         if log_a > log_b { (log_a, log_b) }
         else { (log_b, log_a) };
+    if log_x == -f32::INFINITY {
+        return log_x
+    }
 
     // We have: log(x + y) = log(x * (1 + y/x)) = log x + log(1 + y/x)
     // where y/x = exp(log y) / exp(log x) = exp(log y - log x)
@@ -76,3 +95,114 @@ pub fn compare_f32(x: f32, y: f32) -> Ordering {
         Ordering::Equal
     }
 }
+
+/// Splits `iter_count` iterations among `thread_count` threads and calls `f(rng,
+/// thread_iters)` in every thread, where `thread_iters` is the range of iterations of the
+/// thread. The random generator of every thread is determined by a single seed drawn from
+/// `rng` and by the index of the thread, which selects an independent stream of the
+/// generator. The results are returned in the order of the threads, so that they can be
+/// merged deterministically for a given seed and number of threads.
+pub fn run_parallel<T, F>(
+    rng: &mut dyn RngCore, thread_count: usize,
+    iter_count: usize, f: F,
+) -> Vec<T>
+    where T: Send, F: Fn(&mut dyn RngCore, Range<usize>) -> T + Sync
+{
+    let seed = rng.gen::<u128>();
+    thread::scope(|scope| {
+        let mut iter_begin = 0;
+        let handles = (0..thread_count).map(|thread_i| {
+            let f = &f;
+            let thread_iter_count = iter_count / thread_count
+                + usize::from(thread_i < iter_count % thread_count);
+            let thread_iters = iter_begin..iter_begin + thread_iter_count;
+            iter_begin += thread_iter_count;
+            scope.spawn(move || f(&mut Pcg64::new(seed, thread_i as u128), thread_iters))
+        }).collect::<Vec<_>>();
+        handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+    })
+}
+
+/// Limits the number of iterations of a search or sampling.
+#[derive(Debug, Copy, Clone)]
+pub struct SearchBudget {
+    pub iterations: usize,
+    pub time: Option<Duration>,
+}
+
+impl SearchBudget {
+    /// Returns true if we have already done `iter_count` iterations that were started at
+    /// `start_time` and the budget does not allow more.
+    pub fn is_exhausted(&self, start_time: Instant, iter_count: usize) -> bool {
+        iter_count >= self.iterations
+            || self.time.is_some_and(|time| start_time.elapsed() >= time)
+    }
+}
+
+/// The number of iterations in a batch of `run_batches()`.
+const BATCH_LEN: usize = 200;
+
+/// Runs `f()` in batches of parallel iterations (see `run_parallel()`) until the `budget` is
+/// exhausted or `merge()` returns true. The results of every batch are passed to `merge()`
+/// in the order of the threads. At least one batch is always run (even if the time is
+/// up), unless the budget allows no iterations at all. Returns the number of iterations.
+pub fn run_batches<T, F, M>(
+    rng: &mut dyn RngCore, thread_count: usize,
+    budget: SearchBudget, f: F, mut merge: M,
+) -> usize
+    where T: Send, F: Fn(&mut dyn RngCore, usize) -> T + Sync, M: FnMut(Vec<T>) -> bool
+{
+    let start_time = Instant::now();
+    let mut iter_count = 0;
+    if budget.iterations == 0 {
+        return iter_count
+    }
+    loop {
+        let batch_len = cmp::min(BATCH_LEN, budget.iterations - iter_count);
+        let stop = merge(run_parallel(rng, thread_count, batch_len,
+            |rng, thread_iters| f(rng, thread_iters.len())));
+        iter_count += batch_len;
+        if stop || budget.is_exhausted(start_time, iter_count) {
+            return iter_count
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand_pcg::{Pcg64Mcg};
+    use super::*;
+
+    #[test]
+    fn test_run_batches() {
+        let mut rng = Pcg64Mcg::new(42);
+        let mut batch_lens = Vec::new();
+        let budget = SearchBudget { iterations: 500, time: None };
+        let iter_count = run_batches(&mut rng, 2, budget, |_, iter_count| iter_count,
+            |iter_counts| {
+                batch_lens.push(iter_counts.iter().sum::<usize>());
+                false
+            });
+        assert_eq!(iter_count, 500);
+        assert_eq!(batch_lens, vec![200, 200, 100]);
+
+        // no batch is run without iterations
+        let budget = SearchBudget { iterations: 0, time: None };
+        assert_eq!(run_batches(&mut rng, 2, budget, |_, _| panic!(), |_: Vec<()>| false), 0);
+    }
+
+    #[test]
+    fn test_run_batches_time() {
+        // the time ends the batches long before the iterations
+        let mut rng = Pcg64Mcg::new(42);
+        let time = Some(Duration::from_millis(50));
+        let budget = SearchBudget { iterations: usize::MAX, time };
+        let start_time = Instant::now();
+        let iter_count = run_batches(&mut rng, 2, budget, |_, iter_count| {
+            thread::sleep(Duration::from_millis(1));
+            iter_count
+        }, |_| false);
+        assert!(iter_count >= BATCH_LEN);
+        assert!(start_time.elapsed() < Duration::from_secs(5));
+    }
+}